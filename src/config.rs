@@ -0,0 +1,708 @@
+extern crate log;
+extern crate serde;
+extern crate toml;
+
+use super::world::object::Direction;
+
+/// Configuration file read when no `--config` option is given, if it exists.
+pub const DEFAULT_CONFIG_PATH: &str = "mazemaze.toml";
+
+const USAGE: &str = "usage: mazemaze-server [--config <path>] [--host <address>] [--port <port>] \
+                     [--tick-rate <hz>] [--max-players <count>] [--max-rooms <count>] \
+                     [--log-level <level>] [--width <blocks>] [--height <blocks>] \
+                     [--seed <seed>]";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, std::io::Error),
+    Parse(String, toml::de::Error),
+    Argument(String),
+    Invalid(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "failed to read {}: {}", path, err),
+            ConfigError::Parse(path, err) => write!(f, "failed to parse {}: {}", path, err),
+            ConfigError::Argument(message) => write!(f, "{}\n{}", message, USAGE),
+            ConfigError::Invalid(message) => write!(f, "invalid configuration: {}", message),
+        }
+    }
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    /// Simulation steps per second.
+    pub tick_rate: u32,
+    /// Players allowed in each room.
+    pub max_players: usize,
+    /// Rooms the server hosts at most, including the one created at startup.
+    pub max_rooms: usize,
+    /// Bytes queued for a client at most. A client that falls this far behind loses chat messages
+    /// and hints, and is disconnected when game updates no longer fit; its player is kept for
+    /// `session_grace` like that of a broken connection.
+    pub send_buffer: usize,
+    /// Seconds between two pings of every client, which also measure its latency.
+    pub ping_interval: u64,
+    /// Seconds without hearing from a client after which its connection is considered broken;
+    /// 0 never times clients out.
+    pub idle_timeout: u64,
+    /// Seconds a player whose connection broke is kept for its session to be resumed; 0 removes
+    /// it right away. Players whose client closed the connection always leave at once.
+    pub session_grace: u64,
+    /// Seconds the server keeps sending queued packets to its clients after being asked to stop,
+    /// before dropping the ones that are still behind.
+    pub shutdown_timeout: u64,
+    /// File the persistent rooms are saved to on shutdown and restored from on startup, each
+    /// with its current map; nothing is saved when absent.
+    pub state_file: Option<String>,
+    /// One of `off`, `error`, `warn`, `info`, `debug` or `trace`.
+    pub log_level: String,
+    pub world: WorldConfig,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorldConfig {
+    pub width: u32,
+    pub height: u32,
+    /// Seed for map generation; a random one is picked (and logged) when absent.
+    pub seed: Option<u64>,
+    /// Times the generator restarts after running into a contradiction before giving up. With
+    /// `connectivity = "regenerate"`, also the number of maps generated before giving up.
+    pub generation_attempts: usize,
+    /// What to do when the exit or parts of the floor cannot be reached from the spawn.
+    pub connectivity: Connectivity,
+    /// Number of ticks a player has to wait between two moves.
+    pub move_interval: u32,
+    /// Number of ticks between a player reaching the exit and the next map.
+    pub round_countdown: u32,
+    pub spawn: (i32, i32),
+    pub exit: (i32, i32),
+    /// Learns the tile set from an example map instead of `tiles`' weights and neighbours. The
+    /// sample fills the inside of the border; it should not contain the border itself.
+    pub sample: Option<SampleConfig>,
+    /// Index into `tiles` forced on every cell of the map's border.
+    pub border_tile: Option<usize>,
+    /// Index into `tiles` forced on the spawn and exit cells during generation, before the
+    /// `spawn` and `exit` tiles are placed there. Must be walkable.
+    pub floor_tile: usize,
+    pub tiles: Vec<TileConfig>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Connectivity {
+    /// Turns walls into floor along the shortest corridors joining every floor region to the
+    /// spawn.
+    Carve,
+    /// Generates new maps until the exit is reachable from the spawn.
+    Regenerate,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TileConfig {
+    pub id: u8,
+    #[serde(default)]
+    pub name: String,
+    /// Relative frequency in generated maps. Ignored for `exit` and `spawn` tiles, which are only
+    /// placed on the configured exit and spawn.
+    pub weight: f32,
+    /// Indices into `tiles` that may be placed next to this tile on any side.
+    #[serde(default)]
+    pub neighbors: Vec<usize>,
+    /// Indices into `tiles` that may additionally be placed above this tile.
+    #[serde(default)]
+    pub up: Vec<usize>,
+    #[serde(default)]
+    pub down: Vec<usize>,
+    #[serde(default)]
+    pub left: Vec<usize>,
+    #[serde(default)]
+    pub right: Vec<usize>,
+    #[serde(default)]
+    pub walkable: bool,
+    #[serde(default)]
+    pub opaque: bool,
+    #[serde(default)]
+    pub damaging: bool,
+    #[serde(default)]
+    pub slippery: bool,
+    #[serde(default)]
+    pub exit: bool,
+    #[serde(default)]
+    pub spawn: bool,
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SampleConfig {
+    /// Width and height of the windows learned from the sample.
+    #[serde(default = "default_pattern_size")]
+    pub pattern_size: usize,
+    /// The example map, one string per row, one character per block.
+    pub rows: Vec<String>,
+    /// Block id of every character used in `rows`.
+    pub legend: std::collections::HashMap<char, u8>,
+}
+
+impl SampleConfig {
+    pub fn width(&self) -> usize {
+        self.rows.first().map_or(0, |row| row.chars().count())
+    }
+
+    pub fn height(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// The sample as block ids in row-major order.
+    pub fn blocks(&self) -> Vec<u8> {
+        self.rows
+            .iter()
+            .flat_map(|row| row.chars())
+            .map(|block| self.legend[&block])
+            .collect()
+    }
+
+    /// Checks the sample against the world's tiles; `floor` is the id pinned to the spawn and
+    /// exit, so the sample must contain it.
+    fn validate(&self, tiles: &[TileConfig], floor: u8) -> Result<(), ConfigError> {
+        if self.pattern_size < 2 {
+            return Err(invalid("world.sample.pattern_size must be at least 2"));
+        }
+
+        if self.width() < self.pattern_size || self.height() < self.pattern_size {
+            return Err(invalid(
+                "world.sample must be at least pattern_size blocks in each direction",
+            ));
+        }
+
+        if self
+            .rows
+            .iter()
+            .any(|row| row.chars().count() != self.width())
+        {
+            return Err(invalid("world.sample rows must all have the same length"));
+        }
+
+        if self
+            .rows
+            .iter()
+            .flat_map(|row| row.chars())
+            .any(|block| !self.legend.contains_key(&block))
+        {
+            return Err(invalid(
+                "every character in world.sample.rows must appear in its legend",
+            ));
+        }
+
+        if self.legend.values().any(|id| {
+            !tiles
+                .iter()
+                .any(|tile| tile.id == *id && !tile.exit && !tile.spawn)
+        }) {
+            return Err(invalid(
+                "world.sample.legend must only use ids of world.tiles other than exit and spawn",
+            ));
+        }
+
+        if !self.blocks().contains(&floor) {
+            return Err(invalid(
+                "world.sample must contain the id of world.floor_tile, which is placed on the \
+                 spawn and exit",
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn default_pattern_size() -> usize {
+    3
+}
+
+impl TileConfig {
+    fn new(id: u8, name: &str, weight: f32, neighbors: Vec<usize>) -> TileConfig {
+        TileConfig {
+            id,
+            name: name.to_owned(),
+            weight,
+            neighbors,
+            up: Vec::new(),
+            down: Vec::new(),
+            left: Vec::new(),
+            right: Vec::new(),
+            walkable: false,
+            opaque: false,
+            damaging: false,
+            slippery: false,
+            exit: false,
+            spawn: false,
+        }
+    }
+
+    /// Every neighbour list paired with the direction it applies to.
+    pub fn directional_neighbors(&self) -> [(Direction, &Vec<usize>); 4] {
+        [
+            (Direction::Up, &self.up),
+            (Direction::Down, &self.down),
+            (Direction::Left, &self.left),
+            (Direction::Right, &self.right),
+        ]
+    }
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            host: "0.0.0.0".to_owned(),
+            port: 19980,
+            tick_rate: 20,
+            max_players: 64,
+            max_rooms: 16,
+            send_buffer: super::network::socket::DEFAULT_SEND_BUFFER,
+            ping_interval: 5,
+            idle_timeout: 30,
+            session_grace: 30,
+            shutdown_timeout: 5,
+            state_file: None,
+            log_level: "info".to_owned(),
+            world: WorldConfig::default(),
+        }
+    }
+}
+
+impl Default for WorldConfig {
+    fn default() -> WorldConfig {
+        WorldConfig {
+            width: 40,
+            height: 30,
+            seed: None,
+            sample: None,
+            generation_attempts: super::world::generator::DEFAULT_MAX_ATTEMPTS,
+            connectivity: Connectivity::Carve,
+            move_interval: 2,
+            round_countdown: 100,
+            spawn: (1, 1),
+            exit: (38, 28),
+            border_tile: Some(3),
+            floor_tile: 0,
+            tiles: vec![
+                TileConfig {
+                    walkable: true,
+                    ..TileConfig::new(0, "floor", 2f32, vec![0, 3])
+                },
+                TileConfig {
+                    opaque: true,
+                    ..TileConfig::new(1, "wall", 4f32, vec![1, 2, 3])
+                },
+                TileConfig {
+                    opaque: true,
+                    ..TileConfig::new(2, "hedge", 4f32, vec![1, 2, 3])
+                },
+                TileConfig {
+                    opaque: true,
+                    ..TileConfig::new(3, "border", 1f32, vec![0, 1, 2, 3])
+                },
+                TileConfig {
+                    walkable: true,
+                    exit: true,
+                    ..TileConfig::new(4, "exit", 1f32, Vec::new())
+                },
+                TileConfig {
+                    walkable: true,
+                    spawn: true,
+                    ..TileConfig::new(5, "spawn", 1f32, Vec::new())
+                },
+            ],
+        }
+    }
+}
+
+impl Config {
+    /// Reads the configuration file named by `--config` (or `DEFAULT_CONFIG_PATH` if present),
+    /// then applies the remaining command-line options on top of it.
+    pub fn load<I>(args: I) -> Result<Config, ConfigError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut path = None;
+        let mut overrides = Vec::new();
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| ConfigError::Argument(format!("missing value for {}", arg)))?;
+
+            if arg == "--config" {
+                path = Some(value);
+            } else {
+                overrides.push((arg, value));
+            }
+        }
+
+        let mut config = match path {
+            Some(path) => Config::from_file(&path)?,
+            None if std::path::Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Config::from_file(DEFAULT_CONFIG_PATH)?
+            }
+            None => Config::default(),
+        };
+
+        for (arg, value) in overrides {
+            config.apply(&arg, &value)?;
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Config, ConfigError> {
+        let content =
+            std::fs::read_to_string(path).map_err(|err| ConfigError::Io(path.to_owned(), err))?;
+
+        toml::from_str(&content).map_err(|err| ConfigError::Parse(path.to_owned(), err))
+    }
+
+    pub fn log_level(&self) -> log::LevelFilter {
+        self.log_level.parse().unwrap_or(log::LevelFilter::Info)
+    }
+
+    fn apply(&mut self, arg: &str, value: &str) -> Result<(), ConfigError> {
+        match arg {
+            "--host" => self.host = value.to_owned(),
+            "--port" => self.port = parse(arg, value)?,
+            "--tick-rate" => self.tick_rate = parse(arg, value)?,
+            "--max-players" => self.max_players = parse(arg, value)?,
+            "--max-rooms" => self.max_rooms = parse(arg, value)?,
+            "--log-level" => self.log_level = value.to_owned(),
+            "--width" => self.world.width = parse(arg, value)?,
+            "--height" => self.world.height = parse(arg, value)?,
+            "--seed" => self.world.seed = Some(parse(arg, value)?),
+            _ => return Err(ConfigError::Argument(format!("unknown option {}", arg))),
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.tick_rate == 0 {
+            return Err(invalid("tick_rate must be positive"));
+        }
+
+        if self.max_players == 0 {
+            return Err(invalid("max_players must be positive"));
+        }
+
+        if self.max_rooms == 0 {
+            return Err(invalid("max_rooms must be positive"));
+        }
+
+        if self.send_buffer == 0 {
+            return Err(invalid("send_buffer must be positive"));
+        }
+
+        if self.ping_interval == 0 {
+            return Err(invalid("ping_interval must be positive"));
+        }
+
+        // Clients answering every ping must not time out between two of them.
+        if self.idle_timeout != 0 && self.idle_timeout <= self.ping_interval {
+            return Err(invalid(
+                "idle_timeout must be 0 or longer than ping_interval",
+            ));
+        }
+
+        if self.log_level.parse::<log::LevelFilter>().is_err() {
+            return Err(invalid(
+                "log_level must be off, error, warn, info, debug or trace",
+            ));
+        }
+
+        self.world.validate()
+    }
+}
+
+impl WorldConfig {
+    pub fn contains(&self, (x, y): (i32, i32)) -> bool {
+        0 <= x && x < self.width as i32 && 0 <= y && y < self.height as i32
+    }
+
+    fn on_border(&self, (x, y): (i32, i32)) -> bool {
+        x == 0 || y == 0 || x == self.width as i32 - 1 || y == self.height as i32 - 1
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.width < 3 || self.height < 3 {
+            return Err(invalid("world must be at least 3x3 blocks"));
+        }
+
+        if self.generation_attempts == 0 {
+            return Err(invalid("world.generation_attempts must be positive"));
+        }
+
+        if self.move_interval == 0 {
+            return Err(invalid("world.move_interval must be positive"));
+        }
+
+        if !self.contains(self.spawn) || !self.contains(self.exit) {
+            return Err(invalid(
+                "world.spawn and world.exit must lie inside the world",
+            ));
+        }
+
+        if self.tiles.is_empty() {
+            return Err(invalid("world.tiles must not be empty"));
+        }
+
+        if self.floor_tile >= self.tiles.len()
+            || self
+                .border_tile
+                .is_some_and(|tile| tile >= self.tiles.len())
+        {
+            return Err(invalid(
+                "world.floor_tile and world.border_tile must index world.tiles",
+            ));
+        }
+
+        if !self.tiles[self.floor_tile].walkable {
+            return Err(invalid("world.floor_tile must be walkable"));
+        }
+
+        if std::iter::once(self.floor_tile)
+            .chain(self.border_tile)
+            .any(|tile| self.tiles[tile].exit || self.tiles[tile].spawn)
+        {
+            return Err(invalid(
+                "world.floor_tile and world.border_tile must not be exit or spawn tiles",
+            ));
+        }
+
+        if self
+            .tiles
+            .iter()
+            .any(|tile| (tile.exit || tile.spawn) && !tile.walkable)
+        {
+            return Err(invalid("exit and spawn tiles must be walkable"));
+        }
+
+        if self
+            .tiles
+            .iter()
+            .enumerate()
+            .any(|(index, tile)| self.tiles[..index].iter().any(|other| other.id == tile.id))
+        {
+            return Err(invalid("world.tiles ids must be unique"));
+        }
+
+        if let Some(sample) = &self.sample {
+            sample.validate(&self.tiles, self.tiles[self.floor_tile].id)?;
+
+            // With a border, the sample only fills the blocks inside it.
+            let margin = if self.border_tile.is_some() { 2 } else { 0 };
+
+            if (self.width as usize) < sample.pattern_size + margin
+                || (self.height as usize) < sample.pattern_size + margin
+            {
+                return Err(invalid(
+                    "world is too small for world.sample.pattern_size inside its border",
+                ));
+            }
+
+            if margin != 0 && (self.on_border(self.spawn) || self.on_border(self.exit)) {
+                return Err(invalid(
+                    "world.spawn and world.exit must not lie on the border when using world.sample",
+                ));
+            }
+        }
+
+        for tile in self.tiles.iter() {
+            if tile.weight.is_nan() || tile.weight <= 0f32 {
+                return Err(invalid("tile weights must be positive"));
+            }
+
+            if tile
+                .neighbors
+                .iter()
+                .chain(tile.up.iter())
+                .chain(tile.down.iter())
+                .chain(tile.left.iter())
+                .chain(tile.right.iter())
+                .any(|&index| index >= self.tiles.len())
+            {
+                return Err(invalid(
+                    "tile neighbors, up, down, left and right must index world.tiles",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse<T>(arg: &str, value: &str) -> Result<T, ConfigError>
+where
+    T: std::str::FromStr,
+{
+    value
+        .parse()
+        .map_err(|_| ConfigError::Argument(format!("invalid value for {}: {}", arg, value)))
+}
+
+fn invalid(message: &str) -> ConfigError {
+    ConfigError::Invalid(message.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `content` to a file of its own in the temporary directory and returns its path.
+    fn write(name: &str, content: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "mazemaze-config-{}-{}.toml",
+            std::process::id(),
+            name
+        ));
+
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn load(args: &[&str]) -> Result<Config, ConfigError> {
+        Config::load(args.iter().map(|arg| (*arg).to_owned()))
+    }
+
+    #[test]
+    fn defaults_and_example_are_valid() {
+        assert!(Config::default().validate().is_ok());
+        assert!(load(&["--config", "mazemaze.example.toml"]).is_ok());
+    }
+
+    #[test]
+    fn options_override_the_file() {
+        let path = write(
+            "overrides",
+            "port = 1234\ntick_rate = 10\n[world]\nmove_interval = 3\n",
+        );
+        let config = load(&["--config", &path, "--port", "4321", "--seed", "9"]).unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.port, 4321);
+        assert_eq!(config.tick_rate, 10);
+        assert_eq!(config.world.move_interval, 3);
+        assert_eq!(config.world.seed, Some(9));
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        for (name, content) in [
+            ("top", "prot = 1234\n"),
+            ("world", "[world]\nwidht = 20\n"),
+            (
+                "tile",
+                "[[world.tiles]]\nid = 0\nname = \"floor\"\nweight = 1.0\nsolid = true\n",
+            ),
+        ]
+        .iter()
+        {
+            let path = write(name, content);
+            let result = load(&["--config", &path]);
+
+            std::fs::remove_file(&path).unwrap();
+
+            assert!(matches!(result, Err(ConfigError::Parse(..))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let path = write("invalid", "tick_rate = 0\n");
+        let result = load(&["--config", &path]);
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(ConfigError::Invalid(..))));
+        assert!(matches!(
+            load(&["--tick-rate", "0"]),
+            Err(ConfigError::Invalid(..))
+        ));
+        assert!(matches!(
+            load(&["--width", "2"]),
+            Err(ConfigError::Invalid(..))
+        ));
+        assert!(matches!(
+            load(&["--log-level", "loud"]),
+            Err(ConfigError::Invalid(..))
+        ));
+    }
+
+    #[test]
+    fn samples_must_contain_the_floor() {
+        let sample = |rows: &[&str]| SampleConfig {
+            pattern_size: 2,
+            rows: rows.iter().map(|row| (*row).to_owned()).collect(),
+            legend: [('.', 0), ('x', 1)].iter().cloned().collect(),
+        };
+        let mut config = Config::default();
+
+        config.world.sample = Some(sample(&["x.x", ".x.", "x.x"]));
+        assert!(config.validate().is_ok());
+
+        config.world.sample = Some(sample(&["xxx", "xxx", "xxx"]));
+        match config.validate() {
+            Err(ConfigError::Invalid(message)) => assert!(message.contains("floor_tile")),
+            result => panic!("expected the sample to be rejected, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn neighbors_must_index_the_tiles() {
+        let mut config = Config::default();
+
+        config.world.tiles[0].neighbors.push(6);
+
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(..))));
+
+        for side in 0..4 {
+            let mut config = Config::default();
+            let tile = &mut config.world.tiles[1];
+            let list = [
+                &mut tile.up,
+                &mut tile.down,
+                &mut tile.left,
+                &mut tile.right,
+            ];
+
+            list[side].push(6);
+            assert!(matches!(config.validate(), Err(ConfigError::Invalid(..))));
+        }
+
+        let mut config = Config::default();
+
+        config.world.tiles[1].up.push(5);
+        config.world.tiles[1].right.push(5);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn malformed_options_are_rejected() {
+        assert!(matches!(
+            load(&["--port", "port"]),
+            Err(ConfigError::Argument(..))
+        ));
+        assert!(matches!(
+            load(&["--colour", "red"]),
+            Err(ConfigError::Argument(..))
+        ));
+        assert!(matches!(load(&["--port"]), Err(ConfigError::Argument(..))));
+    }
+}
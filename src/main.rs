@@ -1,12 +1,69 @@
-mod network;
-mod world;
+extern crate env_logger;
+extern crate log;
+extern crate mazemaze_server;
+
+use mazemaze_server::config;
+use mazemaze_server::network;
+use mazemaze_server::world;
 
 fn main() {
-    network::server::start(
-        std::sync::Arc::new(std::sync::Mutex::new(Vec::new())),
-        std::sync::Arc::new(std::sync::Mutex::new(world::world::World::new())),
-        std::sync::Arc::new(std::sync::Mutex::new(network::handler::Handler::new())),
-        "0.0.0.0",
-        19980,
+    let config = match config::Config::load(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
+    env_logger::Builder::new()
+        .filter_level(config.log_level())
+        .init();
+
+    let mut rooms = world::room::RoomManager::new(
+        config.world.clone(),
+        config.max_rooms,
+        config.max_players,
     );
+
+    if let Some(path) = &config.state_file {
+        match rooms.load(path) {
+            Ok(count) => log::info!("restored {} rooms from {}", count, path),
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                log::error!("failed to restore rooms from {}: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if !rooms.rooms().iter().any(|room| room.name() == "main") {
+        if let Err(err) = rooms.create("main", true) {
+            log::error!("failed to create the main room: {}", err);
+            std::process::exit(1);
+        }
+    }
+
+    let rooms = match network::server::start(
+        rooms,
+        network::handler::Handler::new(std::time::Duration::from_secs(config.session_grace)),
+        &config,
+    ) {
+        Ok(rooms) => rooms,
+        Err(err) => {
+            log::error!(
+                "failed to serve on {}:{}: {}",
+                config.host,
+                config.port,
+                err
+            );
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(path) = &config.state_file {
+        match rooms.save(path) {
+            Ok(()) => log::info!("saved the persistent rooms to {}", path),
+            Err(err) => log::error!("failed to save the persistent rooms to {}: {}", path, err),
+        }
+    }
 }
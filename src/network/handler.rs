@@ -1,227 +1,1647 @@
-extern crate byteorder;
+extern crate log;
+extern crate rand;
 
+use super::super::world::player;
+use super::super::world::room::RoomError;
+use super::super::world::room::RoomManager;
+use super::super::world::world::Event;
+use super::super::world::world::World;
 use super::packet;
-use byteorder::ReadBytesExt;
+use super::packet::ClientPacket;
+use super::packet::DisconnectReason;
+use super::packet::JoinFailure;
+use super::packet::PlayerInfo;
+use super::packet::Profile;
+use super::packet::RoomInfo;
+use super::packet::ServerPacket;
+use super::packet::TileChange;
+use super::packet::TileInfo;
+use super::peer::Peer;
+use super::peer::Peers;
+use rand::Rng;
 
-pub enum Context {
-    DirectionReceive,
+/// Longest chat message relayed, in characters.
+pub const MAX_CHAT_LENGTH: usize = 200;
+
+/// Chat messages a client may send in a row before being rate limited.
+pub const CHAT_BURST: u32 = 5;
+
+/// Time it takes a rate limited client to earn one more chat message.
+pub const CHAT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(2);
+
+/// Rooms a client may create in a row before being rate limited, as every one costs a map
+/// generation.
+pub const ROOM_BURST: u32 = 2;
+
+/// Time it takes a rate limited client to earn one more room.
+pub const ROOM_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    AwaitingHello,
+    /// Past the handshake, using the `capabilities` both sides support.
+    Ready {
+        capabilities: u32,
+    },
+    /// Joins `room` once it is generated.
+    Waiting {
+        room: u32,
+        capabilities: u32,
+    },
+    /// Controls `player` in `room`; `token` resumes it from another connection.
+    Joined {
+        room: u32,
+        player: u64,
+        token: u128,
+        capabilities: u32,
+    },
+}
+
+/// A player left in its room after its connection dropped, removed at `expires` unless a client
+/// resumes it first.
+struct Session {
+    room: u32,
+    player: u64,
+    expires: std::time::Instant,
+}
+
+/// Token bucket holding up to `burst` actions and refilled one every `interval`.
+struct RateLimit {
+    burst: u32,
+    interval: std::time::Duration,
+    tokens: u32,
+    refilled: std::time::Instant,
+}
+
+impl RateLimit {
+    fn new(burst: u32, interval: std::time::Duration) -> RateLimit {
+        RateLimit {
+            burst,
+            interval,
+            tokens: burst,
+            refilled: std::time::Instant::now(),
+        }
+    }
+
+    /// Spends one action; returns `false` if none is left.
+    fn take(&mut self, now: std::time::Instant) -> bool {
+        let earned = (now.saturating_duration_since(self.refilled).as_millis()
+            / self.interval.as_millis()) as u32;
+
+        if self.tokens + earned >= self.burst {
+            self.tokens = self.burst;
+            self.refilled = now;
+        } else {
+            self.tokens += earned;
+            self.refilled += self.interval * earned;
+        }
+
+        if self.tokens == 0 {
+            return false;
+        }
+
+        self.tokens -= 1;
+        true
+    }
 }
 
 pub struct Handler {
-    status: std::collections::HashMap<u64, Option<u16>>,
-    context: std::collections::HashMap<u64, Option<Context>>,
+    states: std::collections::HashMap<u64, State>,
+    /// Profiles the clients in `State::Waiting` join with.
+    profiles: std::collections::HashMap<u64, Profile>,
+    chat_limits: std::collections::HashMap<u64, RateLimit>,
+    room_limits: std::collections::HashMap<u64, RateLimit>,
+    sessions: std::collections::HashMap<u128, Session>,
+    session_grace: std::time::Duration,
 }
 
 impl Handler {
-    pub fn new() -> Handler {
+    pub fn new(session_grace: std::time::Duration) -> Handler {
         Handler {
-            status: std::collections::HashMap::new(),
-            context: std::collections::HashMap::new(),
+            states: std::collections::HashMap::new(),
+            profiles: std::collections::HashMap::new(),
+            chat_limits: std::collections::HashMap::new(),
+            room_limits: std::collections::HashMap::new(),
+            sessions: std::collections::HashMap::new(),
+            session_grace,
         }
     }
 
-    pub fn add_socket(&mut self, socket: &mut super::socket::Socket) {
-        socket.receive(2);
-        self.status.insert(socket.id(), None);
-        self.context.insert(socket.id(), None);
+    pub fn add_socket(&mut self, socket: &mut Peer) {
+        self.states.insert(socket.id(), State::AwaitingHello);
+        self.chat_limits
+            .insert(socket.id(), RateLimit::new(CHAT_BURST, CHAT_INTERVAL));
+        self.room_limits
+            .insert(socket.id(), RateLimit::new(ROOM_BURST, ROOM_INTERVAL));
     }
 
+    /// Forgets a dead socket. If `resumable`, its player stays in the room for the session grace
+    /// period.
     pub fn remove_socket(
         &mut self,
-        index: usize,
-        sockets: &mut Vec<super::socket::Socket>,
-        world: &mut super::super::world::world::World,
+        id: u64,
+        resumable: bool,
+        sockets: &mut Peers,
+        rooms: &mut RoomManager,
     ) {
-        self.status.remove(&sockets[index].id());
-        self.context.remove(&sockets[index].id());
+        match self.states.get(&id) {
+            Some(&State::Joined {
+                room,
+                player,
+                token,
+                ..
+            }) if resumable && self.session_grace > std::time::Duration::from_secs(0) => {
+                if let Some(world) = rooms.get_mut(room).map(|room| room.world_mut()) {
+                    if let Some(player) = world
+                        .players_mut()
+                        .iter_mut()
+                        .find(|other| other.id() == player)
+                    {
+                        player.inputs_mut().clear();
+                    }
+                }
 
-        if !world.remove_player(sockets[index].id()) {
-            return;
+                self.sessions.insert(
+                    token,
+                    Session {
+                        room,
+                        player,
+                        expires: std::time::Instant::now() + self.session_grace,
+                    },
+                );
+
+                log::info!(
+                    "keeping player {} of client {} for {}s",
+                    player,
+                    id,
+                    self.session_grace.as_secs()
+                );
+            }
+            _ => self.leave(id, sockets, rooms),
         }
 
-        let packet = packet::player_exit(sockets[index].id());
+        self.states.remove(&id);
+        self.profiles.remove(&id);
+        self.chat_limits.remove(&id);
+        self.room_limits.remove(&id);
+    }
 
-        for socket_index in 0..sockets.len() {
-            if socket_index == index {
-                continue;
+    /// Handles the frames the client sent so far, unless it is closing or already gone.
+    pub fn handle_socket(&mut self, id: u64, sockets: &mut Peers, rooms: &mut RoomManager) {
+        while let Some(frame) = sockets
+            .get_mut(&id)
+            .filter(|socket| !socket.is_closing())
+            .and_then(Peer::retrieve)
+        {
+            match ClientPacket::decode(&frame) {
+                Ok(packet) => self.handle_packet(packet, id, sockets, rooms),
+                Err(err) => log::warn!("dropping packet from {}: {}", id, err),
             }
+        }
+    }
+
+    fn handle_packet(
+        &mut self,
+        packet: ClientPacket,
+        id: u64,
+        sockets: &mut Peers,
+        rooms: &mut RoomManager,
+    ) {
+        let state = self.states[&id];
 
-            sockets[socket_index].send(packet.clone());
+        match packet {
+            ClientPacket::Hello {
+                version,
+                capabilities,
+            } => {
+                if state != State::AwaitingHello {
+                    return;
+                }
+
+                if version != packet::PROTOCOL_VERSION {
+                    log::info!(
+                        "rejecting {}: protocol version {} (capabilities {:#x}), expected {}",
+                        id,
+                        version,
+                        capabilities,
+                        packet::PROTOCOL_VERSION
+                    );
+                    disconnect(peer(sockets, id), DisconnectReason::ProtocolMismatch);
+                    return;
+                }
+
+                self.states.insert(
+                    id,
+                    State::Ready {
+                        capabilities: capabilities & packet::CAPABILITIES,
+                    },
+                );
+                peer(sockets, id).send(
+                    ServerPacket::Welcome {
+                        version: packet::PROTOCOL_VERSION,
+                        capabilities: packet::CAPABILITIES,
+                    }
+                    .encode(),
+                );
+            }
+            _ if state == State::AwaitingHello => {
+                disconnect(peer(sockets, id), DisconnectReason::HandshakeRequired);
+            }
+            ClientPacket::Pong { nonce } => {
+                if !peer(sockets, id).pong(nonce, std::time::Instant::now()) {
+                    log::debug!("unexpected pong {} from {}", nonce, id);
+                }
+            }
+            ClientPacket::ListRooms => {
+                let rooms = rooms
+                    .rooms()
+                    .iter()
+                    .map(|room| RoomInfo {
+                        id: room.id(),
+                        name: room.name().to_owned(),
+                        players: room.world().players().len() as u32,
+                        max_players: rooms.max_players() as u32,
+                        path_length: room.world().path_length() as u32,
+                    })
+                    .collect();
+
+                peer(sockets, id).send(ServerPacket::RoomList { rooms }.encode());
+            }
+            ClientPacket::Join { profile } => {
+                if !matches!(state, State::Ready { .. })
+                    || !check_profile(peer(sockets, id), &profile)
+                {
+                    return;
+                }
+
+                // Matchmaking generates a room when every other one is full, unless it is already
+                // generating one.
+                if rooms.find().is_none()
+                    && rooms.find_generating().is_none()
+                    && !self.may_create_room(id, sockets)
+                {
+                    return;
+                }
+
+                match rooms.find_or_generate() {
+                    Ok(room) if rooms.is_generating(room) => self.wait(id, room, profile),
+                    Ok(room) => self.join(id, room, &profile, sockets, rooms),
+                    // Matchmaking only fails once every room is full and no more can be created.
+                    Err(RoomError::TooManyRooms) => {
+                        disconnect(peer(sockets, id), DisconnectReason::ServerFull);
+                    }
+                    Err(err) => reject(peer(sockets, id), err),
+                }
+            }
+            ClientPacket::CreateRoom { name, profile } => {
+                if !matches!(state, State::Ready { .. })
+                    || !check_profile(peer(sockets, id), &profile)
+                {
+                    return;
+                }
+
+                if !self.may_create_room(id, sockets) {
+                    return;
+                }
+
+                match rooms.generate(&name, false) {
+                    Ok(room) if rooms.is_generating(room) => self.wait(id, room, profile),
+                    Ok(room) => self.join(id, room, &profile, sockets, rooms),
+                    Err(err) => reject(peer(sockets, id), err),
+                }
+            }
+            ClientPacket::JoinRoom { room, profile } => {
+                if !matches!(state, State::Ready { .. })
+                    || !check_profile(peer(sockets, id), &profile)
+                {
+                    return;
+                }
+
+                self.join(id, room, &profile, sockets, rooms);
+            }
+            ClientPacket::Resume { token } => {
+                if !matches!(state, State::Ready { .. }) {
+                    return;
+                }
+
+                self.resume(id, token, sockets, rooms);
+            }
+            ClientPacket::LeaveRoom => {
+                self.leave(id, sockets, rooms);
+            }
+            ClientPacket::Move { direction } => {
+                if let Some((room, player)) = seat(state) {
+                    if let Some(room) = rooms.get_mut(room) {
+                        room.world_mut().queue_input(player, direction);
+                    }
+                }
+            }
+            ClientPacket::Chat { target, text } => {
+                if capabilities(state) & packet::CAPABILITY_CHAT == 0 {
+                    log::debug!("ignoring chat from {}: chat not negotiated", id);
+                    return;
+                }
+
+                if let Some((room, player)) = seat(state) {
+                    self.chat(id, room, player, target, &text, sockets);
+                }
+            }
+            ClientPacket::RequestHint => {
+                if capabilities(state) & packet::CAPABILITY_HINTS == 0 {
+                    log::debug!("ignoring hint request from {}: hints not negotiated", id);
+                    return;
+                }
+
+                if let Some((room, player)) = seat(state) {
+                    if let Some(room) = rooms.get(room) {
+                        peer(sockets, id).offer(
+                            ServerPacket::Hint {
+                                direction: room.world().hint(player),
+                            }
+                            .encode(),
+                        );
+                    }
+                }
+            }
         }
     }
 
-    pub fn handle_sockets(
+    /// Spends one of the rooms the client may create, telling it to wait if none is left.
+    fn may_create_room(&mut self, id: u64, sockets: &mut Peers) -> bool {
+        let allowed = self
+            .room_limits
+            .get_mut(&id)
+            .is_some_and(|limit| limit.take(std::time::Instant::now()));
+
+        if !allowed {
+            log::info!("refusing to create a room for {}: rate limited", id);
+
+            refuse(peer(sockets, id), JoinFailure::RateLimited);
+        }
+
+        allowed
+    }
+
+    /// Seats the socket in `room` once `RoomManager::poll` reports it generated.
+    fn wait(&mut self, id: u64, room: u32, profile: Profile) {
+        let capabilities = capabilities(self.states[&id]);
+
+        self.states
+            .insert(id, State::Waiting { room, capabilities });
+        self.profiles.insert(id, profile);
+    }
+
+    /// Seats the sockets waiting for `room` now that its generation finished, or tells them why
+    /// it failed. A room nobody waits for anymore is dropped again.
+    fn seat_waiting(
         &mut self,
-        sockets: &mut Vec<super::socket::Socket>,
-        world: &mut super::super::world::world::World,
+        room: u32,
+        result: Result<(), RoomError>,
+        sockets: &mut Peers,
+        rooms: &mut RoomManager,
     ) {
-        for index in 0..sockets.len() {
-            self.handle_socket(index, sockets, world);
+        let waiting: Vec<u64> = self
+            .states
+            .iter()
+            .filter(|(_, state)| matches!(state, State::Waiting { room: awaited, .. } if *awaited == room))
+            .map(|(&id, _)| id)
+            .collect();
+
+        for id in waiting {
+            let profile = self.profiles.remove(&id).unwrap();
+
+            self.states.insert(
+                id,
+                State::Ready {
+                    capabilities: capabilities(self.states[&id]),
+                },
+            );
+
+            match result {
+                Ok(()) => self.join(id, room, &profile, sockets, rooms),
+                Err(err) => reject(peer(sockets, id), err),
+            }
         }
+
+        rooms.remove_if_empty(room);
     }
 
-    fn handle_socket(
+    /// Spawns the socket's player in `room`, sends it the world and announces it to the room.
+    fn join(
         &mut self,
-        index: usize,
-        sockets: &mut Vec<super::socket::Socket>,
-        world: &mut super::super::world::world::World,
+        id: u64,
+        room: u32,
+        profile: &Profile,
+        sockets: &mut Peers,
+        rooms: &mut RoomManager,
     ) {
-        let status_code: u16;
+        if let Err(err) = rooms.admit(room) {
+            reject(peer(sockets, id), err);
+            return;
+        }
 
-        {
-            let status = self.status.get_mut(&sockets[index].id()).unwrap();
+        let world = rooms.get_mut(room).unwrap().world_mut();
+        let mut rng = rand::thread_rng();
+        let player = rng.gen::<u64>();
+        let token = rng.gen::<u128>();
 
-            if status.is_none() {
-                let received = sockets[index].retrieve();
+        self.states.insert(
+            id,
+            State::Joined {
+                room,
+                player,
+                token,
+                capabilities: capabilities(self.states[&id]),
+            },
+        );
 
-                if received.is_none() {
-                    return;
-                }
+        world.add_player(player, profile.name.clone(), profile.color);
+        peer(sockets, id).send(inform(world, room, player).encode());
+        peer(sockets, id).send(ServerPacket::Session { token }.encode());
+
+        let packet = ServerPacket::PlayerIncome {
+            player: PlayerInfo::from(world.players().last().unwrap()),
+        }
+        .encode();
+
+        self.broadcast(sockets, room, &packet, Some(id));
+        self.announce(sockets, room, &format!("{} joined", profile.name));
 
-                *status = Some(
-                    std::io::Cursor::new(received.unwrap())
-                        .read_u16::<byteorder::LittleEndian>()
-                        .unwrap(),
+        log::info!(
+            "client {} joined room {} as player {} \"{}\"",
+            id,
+            room,
+            player,
+            profile.name
+        );
+    }
+
+    /// Hands the player of session `token` over to the socket, whether its previous connection
+    /// already dropped or is still open; the latter is closed.
+    fn resume(&mut self, id: u64, token: u128, sockets: &mut Peers, rooms: &mut RoomManager) {
+        let previous = self.states.iter().find_map(|(&other, state)| match *state {
+            State::Joined { token: held, .. } if held == token => Some(other),
+            _ => None,
+        });
+
+        let (room, player) = match (self.sessions.remove(&token), previous) {
+            (Some(session), _) => (session.room, session.player),
+            (None, Some(previous)) => {
+                let state = self.states[&previous];
+                let seated = seat(state).unwrap();
+
+                self.states.insert(
+                    previous,
+                    State::Ready {
+                        capabilities: capabilities(state),
+                    },
                 );
+
+                disconnect(peer(sockets, previous), DisconnectReason::SessionResumed);
+                seated
+            }
+            (None, None) => {
+                log::info!("refusing to resume {}: unknown or expired session", id);
+
+                refuse(peer(sockets, id), JoinFailure::SessionExpired);
+                return;
+            }
+        };
+
+        let world = match rooms.get(room) {
+            Some(room)
+                if room
+                    .world()
+                    .players()
+                    .iter()
+                    .any(|other| other.id() == player) =>
+            {
+                room.world()
             }
+            _ => {
+                log::info!("refusing to resume {}: unknown or expired session", id);
+
+                refuse(peer(sockets, id), JoinFailure::SessionExpired);
+                return;
+            }
+        };
+
+        self.states.insert(
+            id,
+            State::Joined {
+                room,
+                player,
+                token,
+                capabilities: capabilities(self.states[&id]),
+            },
+        );
 
-            status_code = status.unwrap();
+        peer(sockets, id).send(inform(world, room, player).encode());
+        peer(sockets, id).send(ServerPacket::Session { token }.encode());
+
+        log::info!("client {} resumed player {} in room {}", id, player, room);
+    }
+
+    /// Removes the socket's player from its room, if any, and ends its session. A socket waiting
+    /// for its room stops waiting.
+    fn leave(&mut self, id: u64, sockets: &mut Peers, rooms: &mut RoomManager) {
+        let state = self.states[&id];
+
+        if let State::Waiting { capabilities, .. } = state {
+            self.states.insert(id, State::Ready { capabilities });
+            self.profiles.remove(&id);
+            return;
         }
+        let (room, player) = match seat(state) {
+            Some(seated) => seated,
+            None => return,
+        };
 
-        self.handle_packet(status_code, index, sockets, world);
+        self.states.insert(
+            id,
+            State::Ready {
+                capabilities: capabilities(state),
+            },
+        );
+        self.remove_player(room, player, sockets, rooms);
     }
 
-    fn handle_packet(
+    /// Removes a player from its room and tells the players left behind.
+    fn remove_player(&self, room: u32, player: u64, sockets: &mut Peers, rooms: &mut RoomManager) {
+        let mut name = None;
+
+        if let Some(joined) = rooms.get_mut(room) {
+            let world = joined.world_mut();
+
+            name = world
+                .players()
+                .iter()
+                .find(|other| other.id() == player)
+                .map(|other| other.name().to_owned());
+            world.remove_player(player);
+        }
+
+        rooms.remove_if_empty(room);
+
+        let packet = ServerPacket::PlayerExit { player }.encode();
+
+        self.broadcast(sockets, room, &packet, None);
+
+        if let Some(name) = name {
+            self.announce(sockets, room, &format!("{} left", name));
+        }
+    }
+
+    /// Relays a chat message to the room, or to `target` alone if it is set and in the same room.
+    /// Blank messages are ignored; overlong ones, ones with control characters and ones beyond
+    /// the sender's rate limit are dropped and the sender is told why.
+    fn chat(
         &mut self,
-        status: u16,
-        index: usize,
-        sockets: &mut Vec<super::socket::Socket>,
-        world: &mut super::super::world::world::World,
+        id: u64,
+        room: u32,
+        sender: u64,
+        target: Option<u64>,
+        text: &str,
+        sockets: &mut Peers,
     ) {
-        match status {
-            1 => {
-                world.add_player(sockets[index].id());
-
-                sockets[index].send(packet::inform_world(
-                    world.map().width(),
-                    world.map().height(),
-                    world.map().data(),
-                    world.players(),
-                ));
-
-                let player_income_packet = packet::player_income(world.players().last().unwrap());
-
-                for socket_index in 0..sockets.len() {
-                    if socket_index == index {
-                        continue;
+        let text = text.trim();
+
+        if text.is_empty() {
+            return;
+        }
+
+        if text.chars().count() > MAX_CHAT_LENGTH || text.chars().any(char::is_control) {
+            notify(peer(sockets, id), "message not sent: too long or malformed");
+            return;
+        }
+
+        let allowed = self
+            .chat_limits
+            .get_mut(&id)
+            .is_some_and(|limit| limit.take(std::time::Instant::now()));
+
+        if !allowed {
+            notify(
+                peer(sockets, id),
+                "message not sent: you are chatting too fast",
+            );
+            return;
+        }
+
+        let packet = ServerPacket::Chat {
+            sender: Some(sender),
+            whisper: target.is_some(),
+            text: text.to_owned(),
+        }
+        .encode();
+
+        match target {
+            Some(target) => {
+                let recipient = self
+                    .states
+                    .iter()
+                    .find(|(_, state)| seat(**state) == Some((room, target)));
+
+                match recipient {
+                    Some((&recipient, &state))
+                        if capabilities(state) & packet::CAPABILITY_CHAT != 0 =>
+                    {
+                        peer(sockets, recipient).offer(packet)
                     }
+                    Some(..) => notify(
+                        peer(sockets, id),
+                        "message not sent: that player cannot chat",
+                    ),
+                    None => notify(peer(sockets, id), "message not sent: no such player here"),
+                }
+            }
+            None => self.offer_chat(sockets, room, &packet),
+        }
+    }
 
-                    sockets[socket_index].send(player_income_packet.clone());
-                }
-
-                sockets[index].receive(2);
-                self.status.insert(sockets[index].id(), None);
-                self.context.insert(sockets[index].id(), None);
-            }
-            2 => {
-                if self.context[&sockets[index].id()].is_none() {
-                    sockets[index].receive(1);
-                    sockets[index].update();
-                    self.context
-                        .insert(sockets[index].id(), Some(Context::DirectionReceive));
-                }
-
-                match self.context[&sockets[index].id()] {
-                    Some(..) => match sockets[index].retrieve() {
-                        Some(received) => {
-                            let mut allow_movement = false;
-
-                            for player in world.players().iter() {
-                                if player.id() != sockets[index].id() {
-                                    continue;
-                                }
-
-                                allow_movement = match received[0] {
-                                    0 => {
-                                        world.map().get_block(
-                                            player.object().x as u32,
-                                            (player.object().y - 1) as u32,
-                                        ) == 0
-                                    }
-                                    1 => {
-                                        world.map().get_block(
-                                            player.object().x as u32,
-                                            (player.object().y + 1) as u32,
-                                        ) == 0
-                                    }
-                                    2 => {
-                                        world.map().get_block(
-                                            (player.object().x - 1) as u32,
-                                            player.object().y as u32,
-                                        ) == 0
-                                    }
-                                    3 => {
-                                        world.map().get_block(
-                                            (player.object().x + 1) as u32,
-                                            player.object().y as u32,
-                                        ) == 0
-                                    }
-                                    _ => false,
-                                };
-
-                                break;
-                            }
+    /// Sends a server message to everyone in `room`.
+    fn announce(&self, sockets: &mut Peers, room: u32, text: &str) {
+        let packet = ServerPacket::Chat {
+            sender: None,
+            whisper: false,
+            text: text.to_owned(),
+        }
+        .encode();
+
+        self.offer_chat(sockets, room, &packet);
+    }
+
+    /// Pings every client past the handshake, telling each the round-trip time of the previous
+    /// ping.
+    pub fn heartbeat(&mut self, sockets: &mut Peers, now: std::time::Instant) {
+        for socket in sockets.values_mut() {
+            if self.states.get(&socket.id()) == Some(&State::AwaitingHello) {
+                continue;
+            }
 
-                            if allow_movement {
-                                for player in world.players_mut().iter_mut() {
-                                    if player.id() != sockets[index].id() {
-                                        continue;
-                                    }
-
-                                    match received[0] {
-                                        0 => {
-                                            player.object_mut().y -= 1;
-                                        }
-                                        1 => {
-                                            player.object_mut().y += 1;
-                                        }
-                                        2 => {
-                                            player.object_mut().x -= 1;
-                                        }
-                                        3 => {
-                                            player.object_mut().x += 1;
-                                        }
-                                        _ => {}
-                                    }
-
-                                    break;
-                                }
-
-                                let packet = packet::player_move(sockets[index].id(), received[0]);
-
-                                for socket in sockets.iter_mut() {
-                                    socket.send(packet.clone());
-                                }
+            let latency = socket.rtt().map_or(0, |rtt| rtt.as_millis() as u32);
+            let nonce = socket.ping(now);
+
+            socket.offer(ServerPacket::Ping { nonce, latency }.encode());
+        }
+    }
+
+    /// Tells every client the server is going away and closes its socket once that is sent.
+    /// Sessions are dropped, as no client can resume them after the server stops.
+    pub fn shutdown(&mut self, sockets: &mut Peers) {
+        for socket in sockets.values_mut() {
+            disconnect(socket, DisconnectReason::ServerShutdown);
+        }
+
+        self.sessions.clear();
+    }
+
+    /// Seats the clients whose room finished generating, then steps every room once and sends
+    /// what changed in each to its players as a single write per socket.
+    pub fn tick(&mut self, sockets: &mut Peers, rooms: &mut RoomManager) {
+        for (room, result) in rooms.poll() {
+            self.seat_waiting(room, result, sockets, rooms);
+        }
+
+        let now = std::time::Instant::now();
+        let expired: Vec<u128> = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.expires <= now)
+            .map(|(&token, _)| token)
+            .collect();
+
+        for token in expired {
+            let session = self.sessions.remove(&token).unwrap();
+
+            log::info!("session of player {} expired", session.player);
+
+            self.remove_player(session.room, session.player, sockets, rooms);
+        }
+
+        for room in rooms.rooms_mut().iter_mut() {
+            let mut packet = Vec::new();
+            let mut changes = Vec::new();
+            let mut started = false;
+
+            for event in room.world_mut().tick() {
+                match event {
+                    Event::Move { player, direction } => {
+                        packet.extend(ServerPacket::PlayerMove { player, direction }.encode());
+                    }
+                    Event::TileChanged { x, y, tile } => changes.push(TileChange { x, y, tile }),
+                    Event::RoundWon {
+                        player,
+                        ticks,
+                        countdown,
+                    } => {
+                        packet.extend(
+                            ServerPacket::RoundWon {
+                                player,
+                                ticks,
+                                countdown,
                             }
+                            .encode(),
+                        );
+                    }
+                    Event::RoundStarted => started = true,
+                }
+            }
 
-                            sockets[index].receive(2);
-                            self.status.insert(sockets[index].id(), None);
-                            self.context.insert(sockets[index].id(), None);
+            match changes.len() {
+                0 => {}
+                1 => packet.extend(ServerPacket::TileChanged { change: changes[0] }.encode()),
+                _ => packet.extend(ServerPacket::TilesChanged { changes }.encode()),
+            }
+
+            if !packet.is_empty() {
+                self.broadcast(sockets, room.id(), &packet, None);
+            }
+
+            // Every player needs the new map, each with its own player listed first.
+            if started {
+                for socket in sockets.values_mut() {
+                    match self.states.get(&socket.id()).and_then(|state| seat(*state)) {
+                        Some((joined, player)) if joined == room.id() => {
+                            socket.send(inform(room.world(), joined, player).encode());
                         }
-                        None => {}
-                    },
-                    None => {
-                        sockets[index].receive(1);
-                        self.context
-                            .insert(sockets[index].id(), Some(Context::DirectionReceive));
+                        _ => {}
                     }
                 }
             }
-            _ => {
-                sockets[index].receive(2);
-                self.status.insert(sockets[index].id(), None);
-                self.context.insert(sockets[index].id(), None);
+        }
+    }
+
+    /// Sends `packet` to every socket that has joined `room`.
+    fn broadcast(&self, sockets: &mut Peers, room: u32, packet: &[u8], except: Option<u64>) {
+        for (id, socket) in sockets.iter_mut() {
+            if Some(*id) != except && self.room_of(socket) == Some(room) {
+                socket.send(packet.to_vec());
+            }
+        }
+    }
+
+    /// Offers a chat `packet` to every socket that has joined `room` and negotiated
+    /// `CAPABILITY_CHAT`, leaving out clients too far behind to take it.
+    fn offer_chat(&self, sockets: &mut Peers, room: u32, packet: &[u8]) {
+        for socket in sockets.values_mut() {
+            let chats = self
+                .states
+                .get(&socket.id())
+                .is_some_and(|state| capabilities(*state) & packet::CAPABILITY_CHAT != 0);
+
+            if chats && self.room_of(socket) == Some(room) {
+                socket.offer(packet.to_vec());
             }
         }
     }
+
+    fn room_of(&self, socket: &Peer) -> Option<u32> {
+        self.states
+            .get(&socket.id())
+            .and_then(|state| seat(*state))
+            .map(|(room, _)| room)
+    }
+}
+
+/// The whole state of `room` as seen by player `id`, who is listed first.
+fn inform(world: &World, room: u32, id: u64) -> ServerPacket {
+    let players = world
+        .players()
+        .iter()
+        .filter(|player| player.id() == id)
+        .chain(world.players().iter().filter(|player| player.id() != id))
+        .map(PlayerInfo::from)
+        .collect();
+
+    ServerPacket::InformWorld {
+        room,
+        seed: world.seed(),
+        width: world.map().width(),
+        height: world.map().height(),
+        data: world.map().data().clone(),
+        tiles: world.tiles().types().iter().map(TileInfo::from).collect(),
+        players,
+    }
+}
+
+/// Whether the nickname is acceptable, telling the client why it cannot join otherwise.
+fn check_profile(socket: &mut Peer, profile: &Profile) -> bool {
+    if player::is_valid_name(&profile.name) {
+        return true;
+    }
+
+    log::info!("refusing to seat {}: invalid nickname", socket.id());
+
+    refuse(socket, JoinFailure::InvalidNickname);
+
+    false
+}
+
+/// The room and player a state controls, if any.
+fn seat(state: State) -> Option<(u32, u64)> {
+    match state {
+        State::Joined { room, player, .. } => Some((room, player)),
+        _ => None,
+    }
+}
+
+/// The capability bits a state negotiated, none before the handshake.
+fn capabilities(state: State) -> u32 {
+    match state {
+        State::AwaitingHello => 0,
+        State::Ready { capabilities }
+        | State::Waiting { capabilities, .. }
+        | State::Joined { capabilities, .. } => capabilities,
+    }
+}
+
+/// The peer of a connection the simulation thread knows about, as every entry point checks.
+fn peer(sockets: &mut Peers, id: u64) -> &mut Peer {
+    sockets.get_mut(&id).unwrap()
+}
+
+fn reject(socket: &mut Peer, err: RoomError) {
+    let reason = match err {
+        RoomError::NotFound => JoinFailure::NoSuchRoom,
+        RoomError::Full => JoinFailure::RoomFull,
+        RoomError::TooManyRooms => JoinFailure::TooManyRooms,
+        RoomError::InvalidName => JoinFailure::InvalidName,
+        RoomError::Generation(..) => JoinFailure::Unavailable,
+    };
+
+    log::info!("refusing to seat {}: {}", socket.id(), err);
+
+    refuse(socket, reason);
+}
+
+fn refuse(socket: &mut Peer, reason: JoinFailure) {
+    socket.send(ServerPacket::JoinFailed { reason }.encode());
+}
+
+/// Sends a server message to one client, which must have negotiated `CAPABILITY_CHAT`.
+fn notify(socket: &mut Peer, text: &str) {
+    socket.offer(
+        ServerPacket::Chat {
+            sender: None,
+            whisper: true,
+            text: text.to_owned(),
+        }
+        .encode(),
+    );
+}
+
+fn disconnect(socket: &mut Peer, reason: DisconnectReason) {
+    socket.send(ServerPacket::Disconnect { reason }.encode());
+    socket.close();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::peer::Output;
+    use super::*;
+
+    /// A handler with one room and clients connected through channels, as the simulation thread
+    /// drives it.
+    struct Harness {
+        handler: Handler,
+        sockets: Peers,
+        rooms: RoomManager,
+        outputs: std::collections::HashMap<u64, crossbeam::channel::Receiver<Output>>,
+    }
+
+    impl Harness {
+        fn new(session_grace: std::time::Duration) -> Harness {
+            let mut rooms = RoomManager::new(
+                super::super::super::config::WorldConfig {
+                    seed: Some(1),
+                    ..Default::default()
+                },
+                4,
+                8,
+            );
+
+            rooms.create("main", true).unwrap();
+
+            Harness {
+                handler: Handler::new(session_grace),
+                sockets: Peers::new(),
+                rooms,
+                outputs: std::collections::HashMap::new(),
+            }
+        }
+
+        /// Accepts a connection without going through the handshake.
+        fn open(&mut self, id: u64) {
+            let (outputs, received) = crossbeam::channel::unbounded();
+            let mut socket = Peer::new(id, outputs);
+
+            self.handler.add_socket(&mut socket);
+            self.sockets.insert(id, socket);
+            self.outputs.insert(id, received);
+        }
+
+        fn connect(&mut self, id: u64) {
+            self.connect_with(id, packet::CAPABILITIES);
+        }
+
+        fn connect_with(&mut self, id: u64, capabilities: u32) {
+            self.open(id);
+            self.send(
+                id,
+                ClientPacket::Hello {
+                    version: packet::PROTOCOL_VERSION,
+                    capabilities,
+                },
+            );
+        }
+
+        fn send(&mut self, id: u64, packet: ClientPacket) {
+            self.sockets.get_mut(&id).unwrap().push(packet.encode());
+            self.handler
+                .handle_socket(id, &mut self.sockets, &mut self.rooms);
+        }
+
+        fn drop(&mut self, id: u64, resumable: bool) {
+            self.handler
+                .remove_socket(id, resumable, &mut self.sockets, &mut self.rooms);
+            self.sockets.remove(&id);
+        }
+
+        /// The packets sent to the client since the last call, and whether it was closed.
+        fn received(&mut self, id: u64) -> (Vec<ServerPacket>, bool) {
+            let mut packets = Vec::new();
+            let mut closed = false;
+
+            for output in self.outputs[&id].try_iter() {
+                match output {
+                    Output::Send { data, .. } | Output::Offer { data, .. } => {
+                        let mut data = &data[..];
+
+                        // A tick writes the packets of a room back to back.
+                        while let Err(packet::PacketError::TrailingBytes(rest)) =
+                            ServerPacket::decode(data)
+                        {
+                            let (first, rest) = data.split_at(data.len() - rest);
+
+                            packets.push(ServerPacket::decode(first).unwrap());
+                            data = rest;
+                        }
+
+                        packets.push(ServerPacket::decode(data).unwrap());
+                    }
+                    Output::Close { .. } => closed = true,
+                }
+            }
+
+            (packets, closed)
+        }
+
+        /// Ticks until the client no longer waits for its room to be generated.
+        fn settle(&mut self, id: u64) {
+            while matches!(self.handler.states[&id], State::Waiting { .. }) {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+                self.handler.tick(&mut self.sockets, &mut self.rooms);
+            }
+        }
+
+        /// Connects a client and joins the room, returning its player and session token.
+        fn join(&mut self, id: u64) -> (u64, u128) {
+            self.join_with(id, packet::CAPABILITIES)
+        }
+
+        fn join_with(&mut self, id: u64, capabilities: u32) -> (u64, u128) {
+            self.connect_with(id, capabilities);
+            self.send(
+                id,
+                ClientPacket::Join {
+                    profile: profile(id),
+                },
+            );
+
+            let (packets, _) = self.received(id);
+            let token = packets.iter().find_map(|packet| match *packet {
+                ServerPacket::Session { token } => Some(token),
+                _ => None,
+            });
+
+            (seated(&packets).unwrap(), token.unwrap())
+        }
+
+        fn players(&self) -> Vec<u64> {
+            self.rooms
+                .get(1)
+                .unwrap()
+                .world()
+                .players()
+                .iter()
+                .map(|player| player.id())
+                .collect()
+        }
+    }
+
+    /// The nickname of client `id` is `player <id>`.
+    fn profile(id: u64) -> Profile {
+        Profile {
+            name: format!("player {}", id),
+            color: None,
+        }
+    }
+
+    /// The text of every chat message among `packets`.
+    fn chats(packets: &[ServerPacket]) -> Vec<&str> {
+        packets
+            .iter()
+            .filter_map(|packet| match packet {
+                ServerPacket::Chat { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn say(text: &str, target: Option<u64>) -> ClientPacket {
+        ClientPacket::Chat {
+            target,
+            text: text.to_owned(),
+        }
+    }
+
+    /// The player the client was given control of, if it was.
+    fn seated(packets: &[ServerPacket]) -> Option<u64> {
+        packets.iter().find_map(|packet| match packet {
+            ServerPacket::InformWorld { players, .. } => Some(players[0].id),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn dropped_connections_can_be_resumed() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+        let (player, token) = harness.join(1);
+
+        harness.drop(1, true);
+
+        assert_eq!(harness.players(), vec![player]);
+
+        harness.connect(2);
+        harness.received(2);
+        harness.send(2, ClientPacket::Resume { token });
+
+        let (packets, closed) = harness.received(2);
+
+        assert_eq!(seated(&packets), Some(player));
+        assert!(packets.contains(&ServerPacket::Session { token }));
+        assert!(!closed);
+
+        // The session is used up; it takes the new connection's token to resume again.
+        harness.connect(3);
+        harness.received(3);
+        harness.send(3, ClientPacket::Resume { token });
+
+        assert_eq!(
+            harness.received(2),
+            (
+                vec![ServerPacket::Disconnect {
+                    reason: DisconnectReason::SessionResumed
+                }],
+                true
+            )
+        );
+        assert_eq!(seated(&harness.received(3).0), Some(player));
+    }
+
+    #[test]
+    fn resuming_a_live_connection_takes_it_over() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+        let (player, token) = harness.join(1);
+
+        harness.connect(2);
+        harness.received(2);
+        harness.send(2, ClientPacket::Resume { token });
+
+        assert_eq!(
+            harness.received(1),
+            (
+                vec![ServerPacket::Disconnect {
+                    reason: DisconnectReason::SessionResumed
+                }],
+                true
+            )
+        );
+        assert_eq!(seated(&harness.received(2).0), Some(player));
+
+        // The old connection no longer controls the player, so its drop leaves it in place.
+        harness.drop(1, false);
+
+        assert_eq!(harness.players(), vec![player]);
+    }
+
+    #[test]
+    fn sessions_cannot_be_resumed_once_expired() {
+        let mut harness = Harness::new(std::time::Duration::from_millis(1));
+        let (_, token) = harness.join(1);
+
+        harness.drop(1, true);
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        harness
+            .handler
+            .tick(&mut harness.sockets, &mut harness.rooms);
+
+        assert_eq!(harness.players(), Vec::<u64>::new());
+
+        harness.connect(2);
+        harness.received(2);
+        harness.send(2, ClientPacket::Resume { token });
+
+        assert_eq!(
+            harness.received(2),
+            (
+                vec![ServerPacket::JoinFailed {
+                    reason: JoinFailure::SessionExpired
+                }],
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn nicknames_are_checked_as_sent() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+
+        harness.connect(1);
+        harness.received(1);
+
+        for name in [" Ariane", "Ariane ", "\u{a0}Ariane", ""].iter() {
+            harness.send(
+                1,
+                ClientPacket::Join {
+                    profile: Profile {
+                        name: (*name).to_owned(),
+                        color: None,
+                    },
+                },
+            );
+
+            assert_eq!(
+                harness.received(1).0,
+                vec![ServerPacket::JoinFailed {
+                    reason: JoinFailure::InvalidNickname
+                }],
+                "{:?}",
+                name
+            );
+        }
+
+        assert_eq!(harness.players(), Vec::<u64>::new());
+    }
+
+    #[test]
+    fn closed_connections_cannot_be_resumed() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+        let (_, token) = harness.join(1);
+
+        harness.drop(1, false);
+
+        assert_eq!(harness.players(), Vec::<u64>::new());
+
+        harness.connect(2);
+        harness.received(2);
+        harness.send(2, ClientPacket::Resume { token });
+
+        assert_eq!(
+            harness.received(2).0,
+            vec![ServerPacket::JoinFailed {
+                reason: JoinFailure::SessionExpired
+            }]
+        );
+    }
+
+    #[test]
+    fn rate_limits_allow_a_burst_then_refill() {
+        let mut limit = RateLimit::new(3, std::time::Duration::from_secs(2));
+        let start = limit.refilled;
+        let at = |millis| start + std::time::Duration::from_millis(millis);
+
+        assert!(limit.take(start));
+        assert!(limit.take(start));
+        assert!(limit.take(start));
+        assert!(!limit.take(at(1999)));
+
+        // Time left over after earning a token counts towards the next one.
+        assert!(limit.take(at(3000)));
+        assert!(!limit.take(at(3000)));
+        assert!(limit.take(at(4000)));
+        assert!(!limit.take(at(4000)));
+
+        // No more than the burst builds up while idle.
+        for _ in 0..3 {
+            assert!(limit.take(at(60_000)));
+        }
+
+        assert!(!limit.take(at(60_000)));
+    }
+
+    #[test]
+    fn chat_reaches_the_room_and_whispers_their_target() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+        let (first, _) = harness.join(1);
+        let (second, _) = harness.join(2);
+
+        harness.join(3);
+
+        for id in 1..=3 {
+            harness.received(id);
+        }
+
+        harness.send(1, say("  hello  ", None));
+
+        for id in 1..=3 {
+            assert_eq!(
+                harness.received(id).0,
+                vec![ServerPacket::Chat {
+                    sender: Some(first),
+                    whisper: false,
+                    text: "hello".to_owned()
+                }]
+            );
+        }
+
+        harness.send(1, say("psst", Some(second)));
+
+        assert_eq!(
+            harness.received(2).0,
+            vec![ServerPacket::Chat {
+                sender: Some(first),
+                whisper: true,
+                text: "psst".to_owned()
+            }]
+        );
+        assert_eq!(harness.received(1).0, Vec::new());
+        assert_eq!(harness.received(3).0, Vec::new());
+    }
+
+    #[test]
+    fn malformed_and_blank_messages_are_not_relayed() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+
+        harness.join(1);
+        harness.join(2);
+        harness.received(1);
+
+        harness.send(1, say(" \t ", None));
+        harness.send(1, say(&"x".repeat(MAX_CHAT_LENGTH + 1), None));
+        harness.send(1, say("ring \u{7}", None));
+
+        assert_eq!(
+            chats(&harness.received(1).0),
+            vec![
+                "message not sent: too long or malformed",
+                "message not sent: too long or malformed"
+            ]
+        );
+        assert_eq!(harness.received(2).0, Vec::new());
+
+        harness.send(1, say(&"x".repeat(MAX_CHAT_LENGTH), None));
+
+        assert_eq!(chats(&harness.received(2).0).len(), 1);
+    }
+
+    #[test]
+    fn chatting_too_fast_is_refused() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+
+        harness.join(1);
+        harness.join(2);
+        harness.received(1);
+
+        for _ in 0..=CHAT_BURST {
+            harness.send(1, say("spam", None));
+        }
+
+        assert_eq!(chats(&harness.received(2).0).len(), CHAT_BURST as usize);
+        assert_eq!(
+            chats(&harness.received(1).0).last(),
+            Some(&"message not sent: you are chatting too fast")
+        );
+    }
+
+    #[test]
+    fn whispers_need_a_target_in_the_room_that_can_chat() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+
+        harness.join(1);
+
+        let (silent, _) = harness.join_with(2, packet::CAPABILITY_HINTS);
+
+        harness.connect(3);
+        harness.send(
+            3,
+            ClientPacket::CreateRoom {
+                name: "elsewhere".to_owned(),
+                profile: profile(3),
+            },
+        );
+        harness.settle(3);
+
+        let elsewhere = seated(&harness.received(3).0).unwrap();
+
+        harness.received(1);
+        harness.send(1, say("psst", Some(elsewhere)));
+        harness.send(1, say("psst", Some(silent)));
+
+        assert_eq!(
+            chats(&harness.received(1).0),
+            vec![
+                "message not sent: no such player here",
+                "message not sent: that player cannot chat"
+            ]
+        );
+        assert_eq!(harness.received(2).0, Vec::new());
+        assert_eq!(harness.received(3).0, Vec::new());
+    }
+
+    #[test]
+    fn clients_without_chat_neither_send_nor_receive_it() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+
+        harness.join(1);
+        harness.join_with(2, packet::CAPABILITY_HINTS);
+        harness.received(1);
+
+        harness.send(2, say("hello", None));
+
+        assert_eq!(harness.received(1).0, Vec::new());
+
+        harness.send(1, say("hello", None));
+        harness.join(3);
+
+        let (packets, _) = harness.received(2);
+
+        assert_eq!(chats(&packets), Vec::<&str>::new());
+        assert!(packets
+            .iter()
+            .any(|packet| matches!(packet, ServerPacket::PlayerIncome { .. })));
+    }
+
+    #[test]
+    fn joins_and_leaves_are_announced() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+
+        harness.join(1);
+        harness.received(1);
+        harness.join(2);
+
+        assert_eq!(chats(&harness.received(1).0), vec!["player 2 joined"]);
+
+        harness.send(2, ClientPacket::LeaveRoom);
+
+        assert_eq!(chats(&harness.received(1).0), vec!["player 2 left"]);
+
+        harness.join(3);
+        harness.received(1);
+        harness.drop(3, false);
+
+        assert_eq!(chats(&harness.received(1).0), vec!["player 3 left"]);
+    }
+
+    #[test]
+    fn mismatched_versions_are_disconnected() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+
+        harness.open(1);
+        harness.send(
+            1,
+            ClientPacket::Hello {
+                version: packet::PROTOCOL_VERSION - 1,
+                capabilities: packet::CAPABILITIES,
+            },
+        );
+
+        assert_eq!(
+            harness.received(1),
+            (
+                vec![ServerPacket::Disconnect {
+                    reason: DisconnectReason::ProtocolMismatch
+                }],
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn packets_before_hello_are_refused() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+
+        harness.open(1);
+        harness.send(1, ClientPacket::ListRooms);
+
+        assert_eq!(
+            harness.received(1),
+            (
+                vec![ServerPacket::Disconnect {
+                    reason: DisconnectReason::HandshakeRequired
+                }],
+                true
+            )
+        );
+    }
+
+    #[test]
+    fn a_second_hello_is_ignored() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+
+        harness.connect_with(1, packet::CAPABILITY_HINTS);
+
+        assert_eq!(
+            harness.received(1),
+            (
+                vec![ServerPacket::Welcome {
+                    version: packet::PROTOCOL_VERSION,
+                    capabilities: packet::CAPABILITIES,
+                }],
+                false
+            )
+        );
+
+        harness.send(
+            1,
+            ClientPacket::Hello {
+                version: 0,
+                capabilities: packet::CAPABILITIES,
+            },
+        );
+
+        assert_eq!(harness.received(1), (Vec::new(), false));
+
+        // The capabilities of the first hello still apply.
+        harness.send(
+            1,
+            ClientPacket::Join {
+                profile: profile(1),
+            },
+        );
+        harness.received(1);
+        harness.send(1, say("hello", None));
+
+        assert_eq!(harness.received(1), (Vec::new(), false));
+    }
+
+    #[test]
+    fn hints_need_the_capability() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+
+        harness.join(1);
+        harness.join_with(2, packet::CAPABILITY_CHAT);
+        harness.received(1);
+        harness.received(2);
+
+        harness.send(1, ClientPacket::RequestHint);
+        harness.send(2, ClientPacket::RequestHint);
+
+        let (packets, _) = harness.received(1);
+
+        assert!(matches!(
+            packets.as_slice(),
+            [ServerPacket::Hint {
+                direction: Some(..)
+            }]
+        ));
+        assert_eq!(harness.received(2).0, Vec::new());
+    }
+
+    #[test]
+    fn rooms_only_hear_about_their_own_players() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+        let (player, _) = harness.join(1);
+
+        harness.connect(2);
+        harness.send(
+            2,
+            ClientPacket::CreateRoom {
+                name: "elsewhere".to_owned(),
+                profile: profile(2),
+            },
+        );
+
+        // The client joins once the room is generated.
+        assert_eq!(seated(&harness.received(2).0), None);
+
+        harness.settle(2);
+
+        let other = seated(&harness.received(2).0).unwrap();
+        let elsewhere = harness.rooms.rooms()[1].id();
+
+        harness.connect(3);
+        harness.received(1);
+        harness.received(3);
+        harness.send(
+            3,
+            ClientPacket::JoinRoom {
+                room: 1,
+                profile: profile(3),
+            },
+        );
+
+        let incomes = |packets: &[ServerPacket]| -> usize {
+            packets
+                .iter()
+                .filter(|packet| matches!(packet, ServerPacket::PlayerIncome { .. }))
+                .count()
+        };
+
+        assert_eq!(incomes(&harness.received(1).0), 1);
+        assert_eq!(incomes(&harness.received(2).0), 0);
+
+        for room in &[1, elsewhere] {
+            let world = harness.rooms.get_mut(*room).unwrap().world_mut();
+
+            world.set_block(2, 1, 0);
+        }
+
+        let right = super::super::super::world::object::Direction::Right;
+
+        harness.send(1, ClientPacket::Move { direction: right });
+        harness.send(2, ClientPacket::Move { direction: right });
+
+        for _ in 0..4 {
+            harness
+                .handler
+                .tick(&mut harness.sockets, &mut harness.rooms);
+        }
+
+        let moved = |packets: Vec<ServerPacket>| -> Vec<u64> {
+            packets
+                .into_iter()
+                .filter_map(|packet| match packet {
+                    ServerPacket::PlayerMove { player, .. } => Some(player),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        assert_eq!(moved(harness.received(1).0), vec![player]);
+        assert_eq!(moved(harness.received(2).0), vec![other]);
+        assert_eq!(moved(harness.received(3).0), vec![player]);
+    }
+
+    #[test]
+    fn every_seated_client_is_sent_the_next_map() {
+        let mut harness = Harness::new(std::time::Duration::from_secs(30));
+        let (winner, _) = harness.join(1);
+        let (other, _) = harness.join(2);
+
+        harness.connect(3);
+
+        let round = |harness: &Harness| harness.rooms.get(1).unwrap().world().round();
+        let seed = harness.rooms.get(1).unwrap().world().seed();
+
+        harness
+            .rooms
+            .get_mut(1)
+            .unwrap()
+            .world_mut()
+            .set_block(2, 1, 4);
+        harness.send(
+            1,
+            ClientPacket::Move {
+                direction: super::super::super::world::object::Direction::Right,
+            },
+        );
+
+        while let super::super::super::world::world::Round::Running { .. } = round(&harness) {
+            harness
+                .handler
+                .tick(&mut harness.sockets, &mut harness.rooms);
+        }
+
+        for id in 1..=3 {
+            harness.received(id);
+        }
+
+        while let super::super::super::world::world::Round::Won { .. } = round(&harness) {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            harness
+                .handler
+                .tick(&mut harness.sockets, &mut harness.rooms);
+        }
+
+        let next = harness.rooms.get(1).unwrap().world().seed();
+
+        assert_ne!(next, seed);
+
+        for (id, player) in &[(1, winner), (2, other)] {
+            let (packets, _) = harness.received(*id);
+            let informed: Vec<(u64, u64)> = packets
+                .iter()
+                .filter_map(|packet| match packet {
+                    ServerPacket::InformWorld { seed, players, .. } => Some((*seed, players[0].id)),
+                    _ => None,
+                })
+                .collect();
+
+            assert_eq!(informed, vec![(next, *player)]);
+        }
+
+        assert_eq!(harness.received(3).0, Vec::new());
+    }
 }
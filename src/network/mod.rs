@@ -1,4 +1,5 @@
 pub mod handler;
 pub mod packet;
+pub mod peer;
 pub mod server;
 pub mod socket;
@@ -1,103 +1,1245 @@
 extern crate byteorder;
 
+use super::super::world::object::Direction;
+use super::super::world::player::Player;
+use super::super::world::tile::TileType;
+use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
 
-pub fn inform_world(
-	width: u32,
-	height: u32,
-	data: &Vec<u8>,
-	players: &Vec<super::super::world::player::Player>,
-) -> Vec<u8> {
-	let mut packet =
-		Vec::with_capacity(2 + 4 + 4 + data.len() + (8 + 1 + 1 + 1 + 4 + 4) * players.len());
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PacketError {
+	Truncated,
+	TrailingBytes(usize),
+	UnknownOpcode(u16),
+	InvalidDirection(u8),
+	InvalidReason(u8),
+	InvalidUtf8,
+}
 
-	packet.write_u16::<byteorder::LittleEndian>(1).unwrap();
+impl std::fmt::Display for PacketError {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self {
+			PacketError::Truncated => write!(f, "packet is truncated"),
+			PacketError::TrailingBytes(count) => {
+				write!(f, "packet has {} unexpected trailing bytes", count)
+			}
+			PacketError::UnknownOpcode(opcode) => write!(f, "unknown opcode {}", opcode),
+			PacketError::InvalidDirection(direction) => {
+				write!(f, "invalid direction {}", direction)
+			}
+			PacketError::InvalidReason(reason) => write!(f, "invalid disconnect reason {}", reason),
+			PacketError::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+		}
+	}
+}
 
-	packet.write_u32::<byteorder::LittleEndian>(width).unwrap();
-	packet.write_u32::<byteorder::LittleEndian>(height).unwrap();
-	packet.extend(data);
+impl From<std::io::Error> for PacketError {
+	fn from(_: std::io::Error) -> PacketError {
+		PacketError::Truncated
+	}
+}
 
-	packet
-		.write_u32::<byteorder::LittleEndian>(players.len() as u32)
-		.unwrap();
+/// Version of the wire format; bumped whenever a packet layout changes.
+pub const PROTOCOL_VERSION: u16 = 15;
 
-	{
-		let player = players.last().unwrap();
+/// Capability bit for `ClientPacket::RequestHint` and `ServerPacket::Hint`.
+pub const CAPABILITY_HINTS: u32 = 1 << 0;
+
+/// Capability bit for `ClientPacket::Chat` and `ServerPacket::Chat`.
+pub const CAPABILITY_CHAT: u32 = 1 << 1;
+
+/// Capability bits the server advertises in `Welcome`.
+pub const CAPABILITIES: u32 = CAPABILITY_HINTS | CAPABILITY_CHAT;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+	/// The client's `Hello` carried a different protocol version.
+	ProtocolMismatch,
+	/// The client sent another packet before completing the handshake.
+	HandshakeRequired,
+	/// The server already hosts its configured maximum number of players.
+	ServerFull,
+	/// Another connection resumed this connection's session.
+	SessionResumed,
+	/// The server is shutting down.
+	ServerShutdown,
+}
+
+impl DisconnectReason {
+	pub fn from_u8(value: u8) -> Option<DisconnectReason> {
+		match value {
+			0 => Some(DisconnectReason::ProtocolMismatch),
+			1 => Some(DisconnectReason::HandshakeRequired),
+			2 => Some(DisconnectReason::ServerFull),
+			3 => Some(DisconnectReason::SessionResumed),
+			4 => Some(DisconnectReason::ServerShutdown),
+			_ => None,
+		}
+	}
+
+	pub fn to_u8(self) -> u8 {
+		match self {
+			DisconnectReason::ProtocolMismatch => 0,
+			DisconnectReason::HandshakeRequired => 1,
+			DisconnectReason::ServerFull => 2,
+			DisconnectReason::SessionResumed => 3,
+			DisconnectReason::ServerShutdown => 4,
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinFailure {
+	/// No room has the requested id.
+	NoSuchRoom,
+	/// The room already holds its maximum number of players.
+	RoomFull,
+	/// The server cannot host another room.
+	TooManyRooms,
+	/// The requested room name is empty or too long.
+	InvalidName,
+	/// The room's world could not be generated.
+	Unavailable,
+	/// The nickname is empty, too long, starts or ends with a space, or contains characters
+	/// other than letters, digits, spaces, `-`, `_` and `.`.
+	InvalidNickname,
+	/// The session token is unknown, or its grace period is over.
+	SessionExpired,
+	/// The client created rooms too quickly and has to wait before creating another.
+	RateLimited,
+}
+
+impl JoinFailure {
+	pub fn from_u8(value: u8) -> Option<JoinFailure> {
+		match value {
+			0 => Some(JoinFailure::NoSuchRoom),
+			1 => Some(JoinFailure::RoomFull),
+			2 => Some(JoinFailure::TooManyRooms),
+			3 => Some(JoinFailure::InvalidName),
+			4 => Some(JoinFailure::Unavailable),
+			5 => Some(JoinFailure::InvalidNickname),
+			6 => Some(JoinFailure::SessionExpired),
+			7 => Some(JoinFailure::RateLimited),
+			_ => None,
+		}
+	}
+
+	pub fn to_u8(self) -> u8 {
+		match self {
+			JoinFailure::NoSuchRoom => 0,
+			JoinFailure::RoomFull => 1,
+			JoinFailure::TooManyRooms => 2,
+			JoinFailure::InvalidName => 3,
+			JoinFailure::Unavailable => 4,
+			JoinFailure::InvalidNickname => 5,
+			JoinFailure::SessionExpired => 6,
+			JoinFailure::RateLimited => 7,
+		}
+	}
+}
+
+/// How a joining player wants to appear to the others.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
+	pub name: String,
+	/// Preferred color; one is derived from the player id when absent.
+	pub color: Option<(u8, u8, u8)>,
+}
 
+impl Profile {
+	fn encode(&self, packet: &mut Vec<u8>) {
+		write_string(packet, &self.name);
+
+		match self.color {
+			Some((red, green, blue)) => packet.extend(&[1, red, green, blue]),
+			None => packet.push(0),
+		}
+	}
+
+	fn decode(cursor: &mut std::io::Cursor<&[u8]>) -> Result<Profile, PacketError> {
+		let name = read_string(cursor)?;
+		let color = match cursor.read_u8()? {
+			0 => None,
+			_ => Some((cursor.read_u8()?, cursor.read_u8()?, cursor.read_u8()?)),
+		};
+
+		Ok(Profile { name, color })
+	}
+}
+
+/// Wire representation of a room, as sent in `RoomList`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RoomInfo {
+	pub id: u32,
+	pub name: String,
+	pub players: u32,
+	pub max_players: u32,
+	/// Number of moves from the spawn to the exit of the room's current map.
+	pub path_length: u32,
+}
+
+impl RoomInfo {
+	fn encode(&self, packet: &mut Vec<u8>) {
 		packet
-			.write_u64::<byteorder::LittleEndian>(player.id())
+			.write_u32::<byteorder::LittleEndian>(self.id)
+			.unwrap();
+		write_string(packet, &self.name);
+		packet
+			.write_u32::<byteorder::LittleEndian>(self.players)
 			.unwrap();
-
-		packet.push(player.color().0);
-		packet.push(player.color().1);
-		packet.push(player.color().2);
 		packet
-			.write_i32::<byteorder::LittleEndian>(player.object().x)
+			.write_u32::<byteorder::LittleEndian>(self.max_players)
 			.unwrap();
 		packet
-			.write_i32::<byteorder::LittleEndian>(player.object().y)
+			.write_u32::<byteorder::LittleEndian>(self.path_length)
 			.unwrap();
 	}
 
-	for player in players.iter().take(players.len() - 1) {
+	fn decode(cursor: &mut std::io::Cursor<&[u8]>) -> Result<RoomInfo, PacketError> {
+		Ok(RoomInfo {
+			id: cursor.read_u32::<byteorder::LittleEndian>()?,
+			name: read_string(cursor)?,
+			players: cursor.read_u32::<byteorder::LittleEndian>()?,
+			max_players: cursor.read_u32::<byteorder::LittleEndian>()?,
+			path_length: cursor.read_u32::<byteorder::LittleEndian>()?,
+		})
+	}
+}
+
+/// Wire representation of a player, as sent in `InformWorld` and `PlayerIncome`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlayerInfo {
+	pub id: u64,
+	pub name: String,
+	pub color: (u8, u8, u8),
+	pub x: i32,
+	pub y: i32,
+}
+
+impl PlayerInfo {
+	pub fn from(player: &Player) -> PlayerInfo {
+		PlayerInfo {
+			id: player.id(),
+			name: player.name().to_owned(),
+			color: player.color(),
+			x: player.object().x,
+			y: player.object().y,
+		}
+	}
+
+	fn encode(&self, packet: &mut Vec<u8>) {
 		packet
-			.write_u64::<byteorder::LittleEndian>(player.id())
+			.write_u64::<byteorder::LittleEndian>(self.id)
 			.unwrap();
+		write_string(packet, &self.name);
+		packet.push(self.color.0);
+		packet.push(self.color.1);
+		packet.push(self.color.2);
+		packet.write_i32::<byteorder::LittleEndian>(self.x).unwrap();
+		packet.write_i32::<byteorder::LittleEndian>(self.y).unwrap();
+	}
+
+	fn decode(cursor: &mut std::io::Cursor<&[u8]>) -> Result<PlayerInfo, PacketError> {
+		Ok(PlayerInfo {
+			id: cursor.read_u64::<byteorder::LittleEndian>()?,
+			name: read_string(cursor)?,
+			color: (cursor.read_u8()?, cursor.read_u8()?, cursor.read_u8()?),
+			x: cursor.read_i32::<byteorder::LittleEndian>()?,
+			y: cursor.read_i32::<byteorder::LittleEndian>()?,
+		})
+	}
+}
+
+/// Wire representation of a tile type, as sent in `InformWorld`. `flags` combines the
+/// `TileType` property constants.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TileInfo {
+	pub id: u8,
+	pub name: String,
+	pub flags: u8,
+}
+
+impl TileInfo {
+	pub fn from(tile: &TileType) -> TileInfo {
+		TileInfo {
+			id: tile.id,
+			name: tile.name.clone(),
+			flags: tile.flags(),
+		}
+	}
+
+	fn encode(&self, packet: &mut Vec<u8>) {
+		packet.push(self.id);
+		write_string(packet, &self.name);
+		packet.push(self.flags);
+	}
+
+	fn decode(cursor: &mut std::io::Cursor<&[u8]>) -> Result<TileInfo, PacketError> {
+		Ok(TileInfo {
+			id: cursor.read_u8()?,
+			name: read_string(cursor)?,
+			flags: cursor.read_u8()?,
+		})
+	}
+}
+
+/// A replaced block, as sent in `TileChanged` and `TilesChanged`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TileChange {
+	pub x: i32,
+	pub y: i32,
+	pub tile: u8,
+}
+
+impl TileChange {
+	fn encode(&self, packet: &mut Vec<u8>) {
+		packet.write_i32::<byteorder::LittleEndian>(self.x).unwrap();
+		packet.write_i32::<byteorder::LittleEndian>(self.y).unwrap();
+		packet.push(self.tile);
+	}
+
+	fn decode(cursor: &mut std::io::Cursor<&[u8]>) -> Result<TileChange, PacketError> {
+		Ok(TileChange {
+			x: cursor.read_i32::<byteorder::LittleEndian>()?,
+			y: cursor.read_i32::<byteorder::LittleEndian>()?,
+			tile: cursor.read_u8()?,
+		})
+	}
+}
+
+/// Packets sent from a client to the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientPacket {
+	/// Opcode 1: spawn a player for this connection in whichever room has space, creating one if
+	/// needed.
+	Join { profile: Profile },
+	/// Opcode 2: move the connection's player by one block.
+	Move { direction: Direction },
+	/// Opcode 3: the first packet of every connection, announcing the client's wire format.
+	Hello { version: u16, capabilities: u32 },
+	/// Opcode 4: ask for the next move toward the exit. Requires `CAPABILITY_HINTS`.
+	RequestHint,
+	/// Opcode 5: ask for the list of rooms.
+	ListRooms,
+	/// Opcode 6: create a room and join it.
+	CreateRoom { name: String, profile: Profile },
+	/// Opcode 7: join a specific room.
+	JoinRoom { room: u32, profile: Profile },
+	/// Opcode 8: leave the current room without disconnecting.
+	LeaveRoom,
+	/// Opcode 9: say `text` to everyone in the room, or only to player `target`. Requires
+	/// `CAPABILITY_CHAT`.
+	Chat { target: Option<u64>, text: String },
+	/// Opcode 10: take back the player of a dropped connection, using the token from its
+	/// `Session`.
+	Resume { token: u128 },
+	/// Opcode 11: the answer to `Ping`, echoing its nonce.
+	Pong { nonce: u32 },
+}
+
+impl ClientPacket {
+	pub fn opcode(&self) -> u16 {
+		match self {
+			ClientPacket::Join { .. } => 1,
+			ClientPacket::Move { .. } => 2,
+			ClientPacket::Hello { .. } => 3,
+			ClientPacket::RequestHint => 4,
+			ClientPacket::ListRooms => 5,
+			ClientPacket::CreateRoom { .. } => 6,
+			ClientPacket::JoinRoom { .. } => 7,
+			ClientPacket::LeaveRoom => 8,
+			ClientPacket::Chat { .. } => 9,
+			ClientPacket::Resume { .. } => 10,
+			ClientPacket::Pong { .. } => 11,
+		}
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		let mut packet = Vec::new();
 
-		packet.push(player.color().0);
-		packet.push(player.color().1);
-		packet.push(player.color().2);
 		packet
-			.write_i32::<byteorder::LittleEndian>(player.object().x)
+			.write_u16::<byteorder::LittleEndian>(self.opcode())
 			.unwrap();
+
+		match self {
+			ClientPacket::RequestHint | ClientPacket::ListRooms | ClientPacket::LeaveRoom => {}
+			ClientPacket::Join { profile } => {
+				profile.encode(&mut packet);
+			}
+			ClientPacket::Move { direction } => {
+				packet.push(direction.to_u8());
+			}
+			ClientPacket::Hello {
+				version,
+				capabilities,
+			} => {
+				packet
+					.write_u16::<byteorder::LittleEndian>(*version)
+					.unwrap();
+				packet
+					.write_u32::<byteorder::LittleEndian>(*capabilities)
+					.unwrap();
+			}
+			ClientPacket::CreateRoom { name, profile } => {
+				write_string(&mut packet, name);
+				profile.encode(&mut packet);
+			}
+			ClientPacket::JoinRoom { room, profile } => {
+				packet.write_u32::<byteorder::LittleEndian>(*room).unwrap();
+				profile.encode(&mut packet);
+			}
+			ClientPacket::Chat { target, text } => {
+				write_player(&mut packet, *target);
+				write_string(&mut packet, text);
+			}
+			ClientPacket::Resume { token } => {
+				packet
+					.write_u128::<byteorder::LittleEndian>(*token)
+					.unwrap();
+			}
+			ClientPacket::Pong { nonce } => {
+				packet.write_u32::<byteorder::LittleEndian>(*nonce).unwrap();
+			}
+		}
+
+		packet
+	}
+
+	pub fn decode(data: &[u8]) -> Result<ClientPacket, PacketError> {
+		let mut cursor = std::io::Cursor::new(data);
+		let opcode = cursor.read_u16::<byteorder::LittleEndian>()?;
+
+		let packet = match opcode {
+			1 => ClientPacket::Join {
+				profile: Profile::decode(&mut cursor)?,
+			},
+			2 => {
+				let direction = cursor.read_u8()?;
+
+				ClientPacket::Move {
+					direction: Direction::from_u8(direction)
+						.ok_or(PacketError::InvalidDirection(direction))?,
+				}
+			}
+			3 => ClientPacket::Hello {
+				version: cursor.read_u16::<byteorder::LittleEndian>()?,
+				capabilities: cursor.read_u32::<byteorder::LittleEndian>()?,
+			},
+			4 => ClientPacket::RequestHint,
+			5 => ClientPacket::ListRooms,
+			6 => ClientPacket::CreateRoom {
+				name: read_string(&mut cursor)?,
+				profile: Profile::decode(&mut cursor)?,
+			},
+			7 => ClientPacket::JoinRoom {
+				room: cursor.read_u32::<byteorder::LittleEndian>()?,
+				profile: Profile::decode(&mut cursor)?,
+			},
+			8 => ClientPacket::LeaveRoom,
+			9 => ClientPacket::Chat {
+				target: read_player(&mut cursor)?,
+				text: read_string(&mut cursor)?,
+			},
+			10 => ClientPacket::Resume {
+				token: cursor.read_u128::<byteorder::LittleEndian>()?,
+			},
+			11 => ClientPacket::Pong {
+				nonce: cursor.read_u32::<byteorder::LittleEndian>()?,
+			},
+			_ => return Err(PacketError::UnknownOpcode(opcode)),
+		};
+
+		finish(&cursor)?;
+
+		Ok(packet)
+	}
+}
+
+/// Packets sent from the server to a client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ServerPacket {
+	/// Opcode 1: the room joined, its full map, the tile types its blocks refer to, and the
+	/// player list. The first player is the receiver's own.
+	InformWorld {
+		room: u32,
+		seed: u64,
+		width: u32,
+		height: u32,
+		data: Vec<u8>,
+		tiles: Vec<TileInfo>,
+		players: Vec<PlayerInfo>,
+	},
+	/// Opcode 2: another player has joined.
+	PlayerIncome { player: PlayerInfo },
+	/// Opcode 3: a player has left.
+	PlayerExit { player: u64 },
+	/// Opcode 4: a player has moved by one block.
+	PlayerMove { player: u64, direction: Direction },
+	/// Opcode 5: the handshake succeeded.
+	Welcome { version: u16, capabilities: u32 },
+	/// Opcode 6: the server is about to close the connection.
+	Disconnect { reason: DisconnectReason },
+	/// Opcode 7: the answer to `RequestHint`; `None` when the exit is reached or unreachable.
+	Hint { direction: Option<Direction> },
+	/// Opcode 8: one block of the map was replaced.
+	TileChanged { change: TileChange },
+	/// Opcode 9: several blocks of the map were replaced during the same tick.
+	TilesChanged { changes: Vec<TileChange> },
+	/// Opcode 10: the answer to `ListRooms`.
+	RoomList { rooms: Vec<RoomInfo> },
+	/// Opcode 11: a `Join`, `CreateRoom` or `JoinRoom` could not be honoured.
+	JoinFailed { reason: JoinFailure },
+	/// Opcode 12: `player` reached the exit `ticks` ticks after the round started. The room
+	/// moves to a new map, announced by a fresh `InformWorld`, `countdown` ticks later.
+	RoundWon {
+		player: u64,
+		ticks: u64,
+		countdown: u32,
+	},
+	/// Opcode 13: a chat message, from a player or, without `sender`, from the server itself.
+	/// `whisper` marks messages sent to the receiver alone.
+	Chat {
+		sender: Option<u64>,
+		whisper: bool,
+		text: String,
+	},
+	/// Opcode 14: sent after `InformWorld` when joining or resuming. Presenting `token` in a
+	/// `Resume` from a new connection takes the player back for a while after this one drops.
+	Session { token: u128 },
+	/// Opcode 15: sent periodically, to be answered with a `Pong` carrying the same nonce.
+	/// `latency` is the round-trip time measured by the previous ping, in milliseconds, or 0
+	/// before the first one is answered.
+	Ping { nonce: u32, latency: u32 },
+}
+
+impl ServerPacket {
+	pub fn opcode(&self) -> u16 {
+		match self {
+			ServerPacket::InformWorld { .. } => 1,
+			ServerPacket::PlayerIncome { .. } => 2,
+			ServerPacket::PlayerExit { .. } => 3,
+			ServerPacket::PlayerMove { .. } => 4,
+			ServerPacket::Welcome { .. } => 5,
+			ServerPacket::Disconnect { .. } => 6,
+			ServerPacket::Hint { .. } => 7,
+			ServerPacket::TileChanged { .. } => 8,
+			ServerPacket::TilesChanged { .. } => 9,
+			ServerPacket::RoomList { .. } => 10,
+			ServerPacket::JoinFailed { .. } => 11,
+			ServerPacket::RoundWon { .. } => 12,
+			ServerPacket::Chat { .. } => 13,
+			ServerPacket::Session { .. } => 14,
+			ServerPacket::Ping { .. } => 15,
+		}
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		let mut packet = Vec::new();
+
 		packet
-			.write_i32::<byteorder::LittleEndian>(player.object().y)
+			.write_u16::<byteorder::LittleEndian>(self.opcode())
 			.unwrap();
+
+		match self {
+			ServerPacket::InformWorld {
+				room,
+				seed,
+				width,
+				height,
+				data,
+				tiles,
+				players,
+			} => {
+				packet.reserve(
+					4 + 8 + 4 + 4 + data.len() + 4 + (8 + 1 + 1 + 1 + 4 + 4) * players.len(),
+				);
+				packet.write_u32::<byteorder::LittleEndian>(*room).unwrap();
+				packet.write_u64::<byteorder::LittleEndian>(*seed).unwrap();
+				packet.write_u32::<byteorder::LittleEndian>(*width).unwrap();
+				packet
+					.write_u32::<byteorder::LittleEndian>(*height)
+					.unwrap();
+				packet.extend(data);
+				packet
+					.write_u16::<byteorder::LittleEndian>(tiles.len() as u16)
+					.unwrap();
+
+				for tile in tiles.iter() {
+					tile.encode(&mut packet);
+				}
+
+				packet
+					.write_u32::<byteorder::LittleEndian>(players.len() as u32)
+					.unwrap();
+
+				for player in players.iter() {
+					player.encode(&mut packet);
+				}
+			}
+			ServerPacket::PlayerIncome { player } => {
+				player.encode(&mut packet);
+			}
+			ServerPacket::PlayerExit { player } => {
+				packet
+					.write_u64::<byteorder::LittleEndian>(*player)
+					.unwrap();
+			}
+			ServerPacket::PlayerMove { player, direction } => {
+				packet
+					.write_u64::<byteorder::LittleEndian>(*player)
+					.unwrap();
+				packet.push(direction.to_u8());
+			}
+			ServerPacket::Welcome {
+				version,
+				capabilities,
+			} => {
+				packet
+					.write_u16::<byteorder::LittleEndian>(*version)
+					.unwrap();
+				packet
+					.write_u32::<byteorder::LittleEndian>(*capabilities)
+					.unwrap();
+			}
+			ServerPacket::Disconnect { reason } => {
+				packet.push(reason.to_u8());
+			}
+			ServerPacket::Hint { direction } => {
+				packet.push(direction.map_or(NO_DIRECTION, Direction::to_u8));
+			}
+			ServerPacket::TileChanged { change } => {
+				change.encode(&mut packet);
+			}
+			ServerPacket::TilesChanged { changes } => {
+				packet.reserve(4 + (4 + 4 + 1) * changes.len());
+				packet
+					.write_u32::<byteorder::LittleEndian>(changes.len() as u32)
+					.unwrap();
+
+				for change in changes.iter() {
+					change.encode(&mut packet);
+				}
+			}
+			ServerPacket::RoomList { rooms } => {
+				packet
+					.write_u32::<byteorder::LittleEndian>(rooms.len() as u32)
+					.unwrap();
+
+				for room in rooms.iter() {
+					room.encode(&mut packet);
+				}
+			}
+			ServerPacket::JoinFailed { reason } => {
+				packet.push(reason.to_u8());
+			}
+			ServerPacket::RoundWon {
+				player,
+				ticks,
+				countdown,
+			} => {
+				packet
+					.write_u64::<byteorder::LittleEndian>(*player)
+					.unwrap();
+				packet.write_u64::<byteorder::LittleEndian>(*ticks).unwrap();
+				packet
+					.write_u32::<byteorder::LittleEndian>(*countdown)
+					.unwrap();
+			}
+			ServerPacket::Chat {
+				sender,
+				whisper,
+				text,
+			} => {
+				write_player(&mut packet, *sender);
+				packet.push(*whisper as u8);
+				write_string(&mut packet, text);
+			}
+			ServerPacket::Session { token } => {
+				packet
+					.write_u128::<byteorder::LittleEndian>(*token)
+					.unwrap();
+			}
+			ServerPacket::Ping { nonce, latency } => {
+				packet.write_u32::<byteorder::LittleEndian>(*nonce).unwrap();
+				packet
+					.write_u32::<byteorder::LittleEndian>(*latency)
+					.unwrap();
+			}
+		}
+
+		packet
 	}
 
-	packet
+	pub fn decode(data: &[u8]) -> Result<ServerPacket, PacketError> {
+		let mut cursor = std::io::Cursor::new(data);
+		let opcode = cursor.read_u16::<byteorder::LittleEndian>()?;
+
+		let packet = match opcode {
+			1 => {
+				let room = cursor.read_u32::<byteorder::LittleEndian>()?;
+				let seed = cursor.read_u64::<byteorder::LittleEndian>()?;
+				let width = cursor.read_u32::<byteorder::LittleEndian>()?;
+				let height = cursor.read_u32::<byteorder::LittleEndian>()?;
+				let data = read_bytes(&mut cursor, width as usize * height as usize)?;
+				let mut tiles = Vec::new();
+
+				for _ in 0..cursor.read_u16::<byteorder::LittleEndian>()? {
+					tiles.push(TileInfo::decode(&mut cursor)?);
+				}
+
+				let count = cursor.read_u32::<byteorder::LittleEndian>()?;
+				let mut players = Vec::new();
+
+				for _ in 0..count {
+					players.push(PlayerInfo::decode(&mut cursor)?);
+				}
+
+				ServerPacket::InformWorld {
+					room,
+					seed,
+					width,
+					height,
+					data,
+					tiles,
+					players,
+				}
+			}
+			2 => ServerPacket::PlayerIncome {
+				player: PlayerInfo::decode(&mut cursor)?,
+			},
+			3 => ServerPacket::PlayerExit {
+				player: cursor.read_u64::<byteorder::LittleEndian>()?,
+			},
+			4 => {
+				let player = cursor.read_u64::<byteorder::LittleEndian>()?;
+				let direction = cursor.read_u8()?;
+
+				ServerPacket::PlayerMove {
+					player,
+					direction: Direction::from_u8(direction)
+						.ok_or(PacketError::InvalidDirection(direction))?,
+				}
+			}
+			5 => ServerPacket::Welcome {
+				version: cursor.read_u16::<byteorder::LittleEndian>()?,
+				capabilities: cursor.read_u32::<byteorder::LittleEndian>()?,
+			},
+			6 => {
+				let reason = cursor.read_u8()?;
+
+				ServerPacket::Disconnect {
+					reason: DisconnectReason::from_u8(reason)
+						.ok_or(PacketError::InvalidReason(reason))?,
+				}
+			}
+			7 => {
+				let direction = cursor.read_u8()?;
+
+				ServerPacket::Hint {
+					direction: match direction {
+						NO_DIRECTION => None,
+						_ => Some(
+							Direction::from_u8(direction)
+								.ok_or(PacketError::InvalidDirection(direction))?,
+						),
+					},
+				}
+			}
+			8 => ServerPacket::TileChanged {
+				change: TileChange::decode(&mut cursor)?,
+			},
+			9 => {
+				let count = cursor.read_u32::<byteorder::LittleEndian>()?;
+				let mut changes = Vec::new();
+
+				for _ in 0..count {
+					changes.push(TileChange::decode(&mut cursor)?);
+				}
+
+				ServerPacket::TilesChanged { changes }
+			}
+			10 => {
+				let count = cursor.read_u32::<byteorder::LittleEndian>()?;
+				let mut rooms = Vec::new();
+
+				for _ in 0..count {
+					rooms.push(RoomInfo::decode(&mut cursor)?);
+				}
+
+				ServerPacket::RoomList { rooms }
+			}
+			11 => {
+				let reason = cursor.read_u8()?;
+
+				ServerPacket::JoinFailed {
+					reason: JoinFailure::from_u8(reason)
+						.ok_or(PacketError::InvalidReason(reason))?,
+				}
+			}
+			12 => ServerPacket::RoundWon {
+				player: cursor.read_u64::<byteorder::LittleEndian>()?,
+				ticks: cursor.read_u64::<byteorder::LittleEndian>()?,
+				countdown: cursor.read_u32::<byteorder::LittleEndian>()?,
+			},
+			13 => ServerPacket::Chat {
+				sender: read_player(&mut cursor)?,
+				whisper: cursor.read_u8()? != 0,
+				text: read_string(&mut cursor)?,
+			},
+			14 => ServerPacket::Session {
+				token: cursor.read_u128::<byteorder::LittleEndian>()?,
+			},
+			15 => ServerPacket::Ping {
+				nonce: cursor.read_u32::<byteorder::LittleEndian>()?,
+				latency: cursor.read_u32::<byteorder::LittleEndian>()?,
+			},
+			_ => return Err(PacketError::UnknownOpcode(opcode)),
+		};
+
+		finish(&cursor)?;
+
+		Ok(packet)
+	}
 }
 
-pub fn player_income(player: &super::super::world::player::Player) -> Vec<u8> {
-	let mut packet = Vec::with_capacity(2 + 8 + 1 + 1 + 1 + 4 + 4);
+/// Direction byte of a `Hint` that carries no direction.
+const NO_DIRECTION: u8 = 0xff;
 
-	packet.write_u16::<byteorder::LittleEndian>(2).unwrap();
+/// Size of the little-endian `u16` length header that precedes every client packet.
+pub const FRAME_HEADER_SIZE: usize = 2;
 
-	packet
-		.write_u64::<byteorder::LittleEndian>(player.id())
-		.unwrap();
+/// Largest client packet body, in bytes, that the server accepts inside a frame.
+pub const MAX_FRAME_SIZE: usize = 4096;
 
-	packet.push(player.color().0);
-	packet.push(player.color().1);
-	packet.push(player.color().2);
-	packet
-		.write_i32::<byteorder::LittleEndian>(player.object().x)
+/// Prefixes an encoded client packet with its length header.
+pub fn frame(packet: &[u8]) -> Vec<u8> {
+	let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + packet.len());
+
+	frame
+		.write_u16::<byteorder::LittleEndian>(packet.len() as u16)
 		.unwrap();
+	frame.extend(packet);
+
+	frame
+}
+
+fn read_bytes(cursor: &mut std::io::Cursor<&[u8]>, length: usize) -> Result<Vec<u8>, PacketError> {
+	let start = cursor.position() as usize;
+
+	if cursor.get_ref().len() - start < length {
+		return Err(PacketError::Truncated);
+	}
+
+	cursor.set_position((start + length) as u64);
+
+	Ok(cursor.get_ref()[start..start + length].to_vec())
+}
+
+/// Writes `value` preceded by its length in bytes as a little-endian `u16`.
+fn write_string(packet: &mut Vec<u8>, value: &str) {
 	packet
-		.write_i32::<byteorder::LittleEndian>(player.object().y)
+		.write_u16::<byteorder::LittleEndian>(value.len() as u16)
 		.unwrap();
+	packet.extend(value.as_bytes());
+}
 
-	packet
+fn read_string(cursor: &mut std::io::Cursor<&[u8]>) -> Result<String, PacketError> {
+	let length = cursor.read_u16::<byteorder::LittleEndian>()? as usize;
+
+	String::from_utf8(read_bytes(cursor, length)?).map_err(|_| PacketError::InvalidUtf8)
 }
 
-pub fn player_exit(player: u64) -> Vec<u8> {
-	let mut packet = Vec::with_capacity(2 + 8);
+/// Writes an optional player id as a presence byte followed by the id.
+fn write_player(packet: &mut Vec<u8>, player: Option<u64>) {
+	match player {
+		Some(player) => {
+			packet.push(1);
+			packet.write_u64::<byteorder::LittleEndian>(player).unwrap();
+		}
+		None => packet.push(0),
+	}
+}
 
-	packet.write_u16::<byteorder::LittleEndian>(3).unwrap();
+fn read_player(cursor: &mut std::io::Cursor<&[u8]>) -> Result<Option<u64>, PacketError> {
+	match cursor.read_u8()? {
+		0 => Ok(None),
+		_ => Ok(Some(cursor.read_u64::<byteorder::LittleEndian>()?)),
+	}
+}
 
-	packet.write_u64::<byteorder::LittleEndian>(player).unwrap();
+fn finish(cursor: &std::io::Cursor<&[u8]>) -> Result<(), PacketError> {
+	let remaining = cursor.get_ref().len() - cursor.position() as usize;
 
-	packet
+	if remaining == 0 {
+		Ok(())
+	} else {
+		Err(PacketError::TrailingBytes(remaining))
+	}
 }
 
-pub fn player_move(player: u64, direction: u8) -> Vec<u8> {
-	let mut packet = Vec::with_capacity(2 + 8 + 1);
+#[cfg(test)]
+mod tests {
+	use super::*;
 
-	packet.write_u16::<byteorder::LittleEndian>(4).unwrap();
+	fn player_info(id: u64) -> PlayerInfo {
+		PlayerInfo {
+			id,
+			name: format!("player {}", id),
+			color: (64, 128, 255),
+			x: 1,
+			y: -2,
+		}
+	}
+
+	fn client_round_trip(packet: ClientPacket) {
+		assert_eq!(ClientPacket::decode(&packet.encode()), Ok(packet));
+	}
 
-	packet.write_u64::<byteorder::LittleEndian>(player).unwrap();
+	fn server_round_trip(packet: ServerPacket) {
+		assert_eq!(ServerPacket::decode(&packet.encode()), Ok(packet));
+	}
 
-	packet.push(direction);
+	fn profile() -> Profile {
+		Profile {
+			name: "Thésée".to_owned(),
+			color: Some((200, 40, 90)),
+		}
+	}
 
-	packet
+	#[test]
+	fn client_join_round_trip() {
+		client_round_trip(ClientPacket::Join { profile: profile() });
+		client_round_trip(ClientPacket::Join {
+			profile: Profile {
+				name: "ariane".to_owned(),
+				color: None,
+			},
+		});
+	}
+
+	#[test]
+	fn client_move_round_trip() {
+		for direction in [
+			Direction::Up,
+			Direction::Down,
+			Direction::Left,
+			Direction::Right,
+		]
+		.iter()
+		{
+			client_round_trip(ClientPacket::Move {
+				direction: *direction,
+			});
+		}
+	}
+
+	#[test]
+	fn client_hello_round_trip() {
+		client_round_trip(ClientPacket::Hello {
+			version: PROTOCOL_VERSION,
+			capabilities: 0xdead_beef,
+		});
+	}
+
+	#[test]
+	fn client_request_hint_round_trip() {
+		client_round_trip(ClientPacket::RequestHint);
+	}
+
+	#[test]
+	fn client_room_round_trips() {
+		client_round_trip(ClientPacket::ListRooms);
+		client_round_trip(ClientPacket::CreateRoom {
+			name: "labyrinthe".to_owned(),
+			profile: profile(),
+		});
+		client_round_trip(ClientPacket::JoinRoom {
+			room: 3,
+			profile: profile(),
+		});
+		client_round_trip(ClientPacket::LeaveRoom);
+	}
+
+	#[test]
+	fn client_chat_round_trip() {
+		client_round_trip(ClientPacket::Chat {
+			target: None,
+			text: "par ici !".to_owned(),
+		});
+		client_round_trip(ClientPacket::Chat {
+			target: Some(42),
+			text: String::new(),
+		});
+	}
+
+	#[test]
+	fn server_inform_world_round_trip() {
+		server_round_trip(ServerPacket::InformWorld {
+			room: 12,
+			seed: 0x0123_4567_89ab_cdef,
+			width: 3,
+			height: 2,
+			data: vec![0, 1, 2, 3, 0, 1],
+			tiles: vec![
+				TileInfo {
+					id: 0,
+					name: "floor".to_owned(),
+					flags: TileType::WALKABLE,
+				},
+				TileInfo {
+					id: 3,
+					name: "ice".to_owned(),
+					flags: TileType::WALKABLE | TileType::SLIPPERY,
+				},
+			],
+			players: vec![player_info(7), player_info(9)],
+		});
+	}
+
+	#[test]
+	fn server_player_income_round_trip() {
+		server_round_trip(ServerPacket::PlayerIncome {
+			player: player_info(42),
+		});
+	}
+
+	#[test]
+	fn server_player_exit_round_trip() {
+		server_round_trip(ServerPacket::PlayerExit { player: 42 });
+	}
+
+	#[test]
+	fn server_player_move_round_trip() {
+		server_round_trip(ServerPacket::PlayerMove {
+			player: 42,
+			direction: Direction::Left,
+		});
+	}
+
+	#[test]
+	fn server_welcome_round_trip() {
+		server_round_trip(ServerPacket::Welcome {
+			version: PROTOCOL_VERSION,
+			capabilities: CAPABILITIES,
+		});
+	}
+
+	#[test]
+	fn server_disconnect_round_trip() {
+		for reason in [
+			DisconnectReason::ProtocolMismatch,
+			DisconnectReason::HandshakeRequired,
+			DisconnectReason::ServerFull,
+			DisconnectReason::SessionResumed,
+			DisconnectReason::ServerShutdown,
+		]
+		.iter()
+		{
+			server_round_trip(ServerPacket::Disconnect { reason: *reason });
+		}
+	}
+
+	#[test]
+	fn server_hint_round_trip() {
+		server_round_trip(ServerPacket::Hint {
+			direction: Some(Direction::Up),
+		});
+		server_round_trip(ServerPacket::Hint { direction: None });
+	}
+
+	#[test]
+	fn server_tile_changed_round_trip() {
+		server_round_trip(ServerPacket::TileChanged {
+			change: TileChange {
+				x: 3,
+				y: -1,
+				tile: 2,
+			},
+		});
+	}
+
+	#[test]
+	fn server_tiles_changed_round_trip() {
+		server_round_trip(ServerPacket::TilesChanged {
+			changes: vec![
+				TileChange {
+					x: 1,
+					y: 2,
+					tile: 0,
+				},
+				TileChange {
+					x: 4,
+					y: 5,
+					tile: 1,
+				},
+			],
+		});
+		server_round_trip(ServerPacket::TilesChanged {
+			changes: Vec::new(),
+		});
+	}
+
+	#[test]
+	fn server_room_list_round_trip() {
+		server_round_trip(ServerPacket::RoomList {
+			rooms: vec![
+				RoomInfo {
+					id: 1,
+					name: "main".to_owned(),
+					players: 3,
+					max_players: 64,
+					path_length: 74,
+				},
+				RoomInfo {
+					id: 2,
+					name: String::new(),
+					players: 0,
+					max_players: 64,
+					path_length: 0,
+				},
+			],
+		});
+	}
+
+	#[test]
+	fn server_join_failed_round_trip() {
+		for reason in [
+			JoinFailure::NoSuchRoom,
+			JoinFailure::RoomFull,
+			JoinFailure::TooManyRooms,
+			JoinFailure::InvalidName,
+			JoinFailure::Unavailable,
+			JoinFailure::InvalidNickname,
+			JoinFailure::SessionExpired,
+			JoinFailure::RateLimited,
+		]
+		.iter()
+		{
+			server_round_trip(ServerPacket::JoinFailed { reason: *reason });
+		}
+	}
+
+	#[test]
+	fn client_resume_round_trip() {
+		client_round_trip(ClientPacket::Resume {
+			token: 0x0123_4567_89ab_cdef_fedc_ba98_7654_3210,
+		});
+	}
+
+	#[test]
+	fn server_session_round_trip() {
+		server_round_trip(ServerPacket::Session {
+			token: 0x0123_4567_89ab_cdef_fedc_ba98_7654_3210,
+		});
+	}
+
+	#[test]
+	fn heartbeat_round_trips() {
+		client_round_trip(ClientPacket::Pong { nonce: 0xdead_beef });
+		server_round_trip(ServerPacket::Ping {
+			nonce: 0xdead_beef,
+			latency: 42,
+		});
+	}
+
+	#[test]
+	fn server_chat_round_trip() {
+		server_round_trip(ServerPacket::Chat {
+			sender: Some(42),
+			whisper: true,
+			text: "la sortie est à l'est".to_owned(),
+		});
+		server_round_trip(ServerPacket::Chat {
+			sender: None,
+			whisper: false,
+			text: "Ariane joined".to_owned(),
+		});
+	}
+
+	#[test]
+	fn server_round_won_round_trip() {
+		server_round_trip(ServerPacket::RoundWon {
+			player: 0x0123_4567_89ab_cdef,
+			ticks: 1234,
+			countdown: 100,
+		});
+	}
+
+	#[test]
+	fn frame_prefixes_length() {
+		let packet = ClientPacket::Move {
+			direction: Direction::Down,
+		}
+		.encode();
+
+		assert_eq!(frame(&packet), vec![3, 0, 2, 0, 1]);
+	}
+
+	#[test]
+	fn truncated_packets_are_rejected() {
+		assert_eq!(ClientPacket::decode(&[2]), Err(PacketError::Truncated));
+		assert_eq!(ClientPacket::decode(&[2, 0]), Err(PacketError::Truncated));
+
+		let encoded = ServerPacket::InformWorld {
+			room: 1,
+			seed: 1,
+			width: 2,
+			height: 2,
+			data: vec![0; 4],
+			tiles: vec![TileInfo {
+				id: 0,
+				name: "floor".to_owned(),
+				flags: TileType::WALKABLE,
+			}],
+			players: vec![player_info(1)],
+		}
+		.encode();
+
+		for length in 0..encoded.len() {
+			assert_eq!(
+				ServerPacket::decode(&encoded[..length]),
+				Err(PacketError::Truncated)
+			);
+		}
+	}
+
+	#[test]
+	fn unknown_opcodes_are_rejected() {
+		assert_eq!(
+			ClientPacket::decode(&[0xff, 0x00]),
+			Err(PacketError::UnknownOpcode(0xff))
+		);
+		assert_eq!(
+			ServerPacket::decode(&[0x00, 0x00]),
+			Err(PacketError::UnknownOpcode(0))
+		);
+	}
+
+	#[test]
+	fn invalid_payloads_are_rejected() {
+		assert_eq!(
+			ClientPacket::decode(&[2, 0, 4]),
+			Err(PacketError::InvalidDirection(4))
+		);
+		assert_eq!(
+			ClientPacket::decode(&[4, 0, 0]),
+			Err(PacketError::TrailingBytes(1))
+		);
+		assert_eq!(ClientPacket::decode(&[1, 0]), Err(PacketError::Truncated));
+		assert_eq!(
+			ServerPacket::decode(&[6, 0, 0xff]),
+			Err(PacketError::InvalidReason(0xff))
+		);
+		assert_eq!(
+			ServerPacket::decode(&[7, 0, 4]),
+			Err(PacketError::InvalidDirection(4))
+		);
+		assert_eq!(
+			ServerPacket::decode(&[
+				1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 1, 0,
+				0xff, 0
+			]),
+			Err(PacketError::InvalidUtf8)
+		);
+	}
 }
@@ -0,0 +1,232 @@
+extern crate crossbeam;
+
+/// Frames a client may have waiting to be handled. Only a client flooding the server gets to
+/// this many between two rounds of the simulation thread, and it is disconnected.
+pub const MAX_PENDING_FRAMES: usize = 256;
+
+/// The connections known to the simulation thread, by id.
+pub type Peers = std::collections::HashMap<u64, Peer>;
+
+/// What the network thread tells the simulation thread about its connections.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    Connected {
+        id: u64,
+    },
+    /// A frame the client sent, in the order received.
+    Frame {
+        id: u64,
+        data: Vec<u8>,
+    },
+    /// The connection is gone. It is `resumable` if it broke rather than being closed by the
+    /// client.
+    Disconnected {
+        id: u64,
+        resumable: bool,
+    },
+}
+
+/// What the simulation thread asks the network thread to do with a connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Output {
+    /// Queues a packet with `Socket::send`.
+    Send { id: u64, data: Vec<u8> },
+    /// Queues a packet with `Socket::offer`.
+    Offer { id: u64, data: Vec<u8> },
+    /// Closes the socket once the packets queued before are flushed.
+    Close { id: u64 },
+}
+
+/// The simulation thread's view of a connection owned by the network thread. Frames received
+/// from the client are pushed into it, and packets queued on it are handed over as `Output`s.
+pub struct Peer {
+    id: u64,
+    closing: bool,
+    inbox: std::collections::VecDeque<Vec<u8>>,
+    ping: Option<(u32, std::time::Instant)>,
+    rtt: Option<std::time::Duration>,
+    outputs: crossbeam::channel::Sender<Output>,
+}
+
+impl Peer {
+    pub fn new(id: u64, outputs: crossbeam::channel::Sender<Output>) -> Peer {
+        Peer {
+            id,
+            closing: false,
+            inbox: std::collections::VecDeque::new(),
+            ping: None,
+            rtt: None,
+            outputs,
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Round-trip time measured by the last answered ping.
+    pub fn rtt(&self) -> Option<std::time::Duration> {
+        self.rtt
+    }
+
+    /// Starts a round-trip measurement, replacing any unanswered one, and returns the nonce the
+    /// client has to echo.
+    pub fn ping(&mut self, now: std::time::Instant) -> u32 {
+        let nonce = self.ping.map_or(0, |(nonce, _)| nonce.wrapping_add(1));
+
+        self.ping = Some((nonce, now));
+
+        nonce
+    }
+
+    /// Completes the measurement started by `ping`; returns `false` if `nonce` is not the one
+    /// awaited.
+    pub fn pong(&mut self, nonce: u32, now: std::time::Instant) -> bool {
+        match self.ping {
+            Some((awaited, sent)) if awaited == nonce => {
+                self.rtt = Some(now.saturating_duration_since(sent));
+                self.ping = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Queues a packet the client cannot do without; see `Socket::send`.
+    pub fn send(&mut self, data: Vec<u8>) {
+        if !self.closing {
+            self.output(Output::Send { id: self.id, data });
+        }
+    }
+
+    /// Queues a packet the client can do without; see `Socket::offer`.
+    pub fn offer(&mut self, data: Vec<u8>) {
+        if !self.closing {
+            self.output(Output::Offer { id: self.id, data });
+        }
+    }
+
+    /// Stores a frame received from the client until it is retrieved; returns `false` if
+    /// `MAX_PENDING_FRAMES` are already waiting. Frames for a closing peer are dropped.
+    pub fn push(&mut self, data: Vec<u8>) -> bool {
+        if self.closing {
+            return true;
+        }
+
+        if self.inbox.len() >= MAX_PENDING_FRAMES {
+            return false;
+        }
+
+        self.inbox.push_back(data);
+        true
+    }
+
+    pub fn retrieve(&mut self) -> Option<Vec<u8>> {
+        self.inbox.pop_front()
+    }
+
+    /// Stops accepting packets from and for the client, and has the network thread close the
+    /// connection once the packets queued so far are sent.
+    pub fn close(&mut self) {
+        if !self.closing {
+            self.closing = true;
+            self.inbox.clear();
+            self.output(Output::Close { id: self.id });
+        }
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    fn output(&self, output: Output) {
+        // Once the network thread is gone, there is nobody left to send to.
+        self.outputs.send(output).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_are_handed_over_in_order() {
+        let (outputs, received) = crossbeam::channel::unbounded();
+        let mut peer = Peer::new(7, outputs);
+
+        peer.send(vec![1]);
+        peer.offer(vec![2]);
+
+        assert_eq!(
+            received.try_iter().collect::<Vec<_>>(),
+            vec![
+                Output::Send {
+                    id: 7,
+                    data: vec![1]
+                },
+                Output::Offer {
+                    id: 7,
+                    data: vec![2]
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn closing_drops_later_packets_and_frames() {
+        let (outputs, received) = crossbeam::channel::unbounded();
+        let mut peer = Peer::new(7, outputs);
+
+        peer.push(vec![1]);
+        peer.close();
+        peer.close();
+        peer.send(vec![2]);
+        peer.offer(vec![3]);
+
+        assert!(peer.is_closing());
+        assert_eq!(peer.retrieve(), None);
+        assert_eq!(
+            received.try_iter().collect::<Vec<_>>(),
+            vec![Output::Close { id: 7 }]
+        );
+    }
+
+    #[test]
+    fn inbox_is_bounded() {
+        let (outputs, _received) = crossbeam::channel::unbounded();
+        let mut peer = Peer::new(7, outputs);
+
+        for frame in 0..MAX_PENDING_FRAMES {
+            assert!(peer.push(vec![frame as u8]));
+        }
+
+        assert!(!peer.push(vec![0]));
+        assert_eq!(peer.retrieve(), Some(vec![0]));
+        assert!(peer.push(vec![0]));
+    }
+
+    #[test]
+    fn pong_measures_the_awaited_ping() {
+        let (outputs, _received) = crossbeam::channel::unbounded();
+        let mut peer = Peer::new(7, outputs);
+        let sent = std::time::Instant::now();
+        let nonce = peer.ping(sent);
+
+        assert!(!peer.pong(nonce.wrapping_add(1), sent));
+        assert!(peer.pong(nonce, sent + std::time::Duration::from_millis(30)));
+        assert!(!peer.pong(nonce, sent));
+        assert_eq!(peer.rtt(), Some(std::time::Duration::from_millis(30)));
+    }
+
+    #[test]
+    fn sending_after_the_network_thread_is_gone_is_ignored() {
+        let (outputs, received) = crossbeam::channel::unbounded();
+        let mut peer = Peer::new(7, outputs);
+
+        drop(received);
+        peer.send(vec![1]);
+        peer.close();
+
+        assert!(peer.is_closing());
+    }
+}
@@ -1,78 +1,469 @@
 extern crate crossbeam;
+extern crate log;
+extern crate mio;
+extern crate signal_hook;
 
+use super::peer::Input;
+use super::peer::Output;
+
+const LISTENER: mio::Token = mio::Token(0);
+const WAKER: mio::Token = mio::Token(1);
+const SIGNAL: mio::Token = mio::Token(2);
+
+/// Serves clients until the process receives SIGINT or SIGTERM, then returns the rooms. Fails
+/// if the server cannot listen on the configured address.
+///
+/// The calling thread accepts connections and does all socket I/O, while a simulation thread
+/// owns the rooms and the handler. The two only talk through channels: the network thread
+/// reports connections and the frames they carry as `Input`s, and the simulation thread answers
+/// with `Output`s, waking the network thread's poll whenever it queues some.
 pub fn start(
-    sockets: std::sync::Arc<std::sync::Mutex<Vec<super::socket::Socket>>>,
-    world: std::sync::Arc<std::sync::Mutex<super::super::world::world::World>>,
-    handler: std::sync::Arc<std::sync::Mutex<super::handler::Handler>>,
-    host: &str,
-    port: u16,
-) {
-    crossbeam::scope(|s| {
-        s.spawn(|_| loop {
-            {
-                let mut sockets = sockets.lock().unwrap();
-                let mut world = world.lock().unwrap();
-                let mut handler = handler.lock().unwrap();
-
-                for index in (0..sockets.len()).rev() {
-                    if !sockets[index].update() {
-                        println!("current players: {}", sockets.len());
-                        println!("player exit: {}, {}", index, sockets[index].id());
-
-                        sockets[index]
-                            .stream()
-                            .shutdown(std::net::Shutdown::Both)
-                            .or_else(|_| std::io::Result::Ok(()))
-                            .unwrap();
-
-                        handler.remove_socket(index, &mut sockets, &mut world);
-                        sockets.remove(index);
+    rooms: super::super::world::room::RoomManager,
+    handler: super::handler::Handler,
+    config: &super::super::config::Config,
+) -> std::io::Result<super::super::world::room::RoomManager> {
+    let mut poll = mio::Poll::new()?;
+    let mut events = mio::Events::with_capacity(1024);
+    let mut listener = bind(&config.host, config.port)?;
+    let mut next_token = SIGNAL.0 + 1;
+    let ping_interval = std::time::Duration::from_secs(config.ping_interval);
+    let idle_timeout = match config.idle_timeout {
+        0 => None,
+        seconds => Some(std::time::Duration::from_secs(seconds)),
+    };
+    let mut next_heartbeat = std::time::Instant::now() + ping_interval;
+    let mut sockets: std::collections::HashMap<mio::Token, super::socket::Socket> =
+        std::collections::HashMap::new();
+    let mut tokens: std::collections::HashMap<u64, mio::Token> = std::collections::HashMap::new();
+    let shutdown = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let (notifier, signals) = std::os::unix::net::UnixStream::pair()?;
+
+    signals.set_nonblocking(true)?;
+
+    let mut signals = mio::net::UnixStream::from_std(signals);
+
+    // The first signal asks the server to stop; a second one stops it at once. Any thread may
+    // receive the signal, so it is also written to a pipe that wakes the poll below.
+    for &signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM].iter() {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, shutdown.clone())?;
+        signal_hook::flag::register(signal, shutdown.clone())?;
+        signal_hook::low_level::pipe::register(signal, notifier.try_clone()?)?;
+    }
+
+    poll.registry()
+        .register(&mut listener, LISTENER, mio::Interest::READABLE)?;
+    poll.registry()
+        .register(&mut signals, SIGNAL, mio::Interest::READABLE)?;
+
+    let waker = std::sync::Arc::new(mio::Waker::new(poll.registry(), WAKER)?);
+    let (inputs, simulation_inputs) = crossbeam::channel::unbounded();
+    let (simulation_outputs, outputs) = crossbeam::channel::unbounded();
+    let simulation = {
+        let waker = waker.clone();
+        let tick_interval = std::time::Duration::from_secs(1) / config.tick_rate;
+
+        std::thread::Builder::new()
+            .name("simulation".to_owned())
+            .spawn(move || {
+                simulate(
+                    rooms,
+                    handler,
+                    simulation_inputs,
+                    simulation_outputs,
+                    waker,
+                    tick_interval,
+                    ping_interval,
+                )
+            })?
+    };
+
+    log::info!("listening on {}", listener.local_addr()?);
+
+    while !shutdown.load(std::sync::atomic::Ordering::SeqCst) {
+        // Only connected clients can time out; without any, the loop sleeps until woken.
+        let timeout = match idle_timeout {
+            Some(..) if !sockets.is_empty() => {
+                Some(next_heartbeat.saturating_duration_since(std::time::Instant::now()))
+            }
+            _ => None,
+        };
+
+        if let Err(err) = poll.poll(&mut events, timeout) {
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+
+            panic!("failed to poll sockets: {}", err);
+        }
+
+        let mut dead = Vec::new();
+
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => {
+                    accept(
+                        poll.registry(),
+                        &mut listener,
+                        &mut next_token,
+                        config.send_buffer,
+                        &mut sockets,
+                        &mut tokens,
+                        &inputs,
+                    );
+                    continue;
+                }
+                // Outputs are delivered below after every wakeup, and the flag is checked by the
+                // loop itself.
+                WAKER | SIGNAL => continue,
+                _ => {}
+            }
+
+            let socket = match sockets.get_mut(&event.token()) {
+                Some(socket) => socket,
+                None => continue,
+            };
+            let status = socket.update();
+
+            // Whatever the client sent before leaving is still handled.
+            while let Some(data) = socket.retrieve() {
+                inputs
+                    .send(Input::Frame {
+                        id: socket.id(),
+                        data,
+                    })
+                    .ok();
+            }
+
+            if status != super::socket::Status::Open {
+                dead.push((event.token(), status));
+            }
+        }
+
+        for output in outputs.try_iter() {
+            deliver(output, &mut sockets, &tokens);
+        }
+
+        let now = std::time::Instant::now();
+
+        if next_heartbeat <= now {
+            if let Some(idle_timeout) = idle_timeout {
+                for (&token, socket) in sockets.iter() {
+                    if now.saturating_duration_since(socket.last_seen()) >= idle_timeout {
+                        dead.push((token, super::socket::Status::TimedOut));
                     }
                 }
             }
 
-            std::thread::sleep(std::time::Duration::from_millis(1u64));
+            next_heartbeat = now + ping_interval;
+        }
 
-            {
-                let mut sockets = sockets.lock().unwrap();
-                let mut world = world.lock().unwrap();
-                let mut handler = handler.lock().unwrap();
+        loop {
+            for (&token, socket) in sockets.iter_mut() {
+                if !socket.has_pending() && !socket.is_closing() {
+                    continue;
+                }
+
+                match socket.flush() {
+                    super::socket::Status::Open => {}
+                    status => dead.push((token, status)),
+                }
+            }
+
+            if dead.is_empty() {
+                break;
+            }
+
+            // A socket may have died several ways at once; the first one found is reported.
+            for (token, status) in dead.drain(..) {
+                let mut socket = match sockets.remove(&token) {
+                    Some(socket) => socket,
+                    None => continue,
+                };
+
+                tokens.remove(&socket.id());
+
+                log::info!(
+                    "client {} disconnected ({}), {} remaining",
+                    socket.id(),
+                    status,
+                    sockets.len()
+                );
+
+                poll.registry().deregister(socket.stream()).ok();
+                socket.stream().shutdown(std::net::Shutdown::Both).ok();
+
+                // Only a broken or lagging connection may come back; a client that hung up has
+                // left.
+                let resumable = matches!(
+                    status,
+                    super::socket::Status::Failed(..)
+                        | super::socket::Status::TimedOut
+                        | super::socket::Status::Overflowed
+                );
 
-                handler.handle_sockets(&mut sockets, &mut world);
+                inputs
+                    .send(Input::Disconnected {
+                        id: socket.id(),
+                        resumable,
+                    })
+                    .ok();
             }
+        }
+    }
+
+    poll.registry().deregister(&mut listener).ok();
+    drop(listener);
+
+    let deadline =
+        std::time::Instant::now() + std::time::Duration::from_secs(config.shutdown_timeout);
+
+    log::info!("shutting down, disconnecting {} clients", sockets.len());
+
+    // Hanging up tells the simulation to say goodbye to every client and return the rooms.
+    drop(inputs);
+
+    let rooms = simulation.join().unwrap();
 
-            std::thread::sleep(std::time::Duration::from_millis(1u64));
+    for output in outputs.try_iter() {
+        deliver(output, &mut sockets, &tokens);
+    }
+
+    loop {
+        sockets.retain(|_, socket| match socket.flush() {
+            super::socket::Status::Open => true,
+            _ => {
+                socket.stream().shutdown(std::net::Shutdown::Both).ok();
+                false
+            }
         });
-        s.spawn(|_| {
-            let listener = std::net::TcpListener::bind(format!("{}:{}", host, port)).unwrap();
 
-            println!("listening...");
+        let now = std::time::Instant::now();
+
+        if sockets.is_empty() || deadline <= now {
+            break;
+        }
+
+        if let Err(err) = poll.poll(&mut events, Some(deadline - now)) {
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                log::error!("failed to poll sockets: {}", err);
+                break;
+            }
+        }
+    }
+
+    if !sockets.is_empty() {
+        log::warn!(
+            "dropping {} clients that did not receive everything in time",
+            sockets.len()
+        );
+    }
 
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => {
-                        if stream.set_nodelay(false).is_err() {
-                            continue;
-                        }
+    Ok(rooms)
+}
+
+/// Listens on the first address `host` resolves to that can be bound.
+fn bind(host: &str, port: u16) -> std::io::Result<mio::net::TcpListener> {
+    let mut failure = None;
+
+    for address in std::net::ToSocketAddrs::to_socket_addrs(&(host, port))? {
+        match mio::net::TcpListener::bind(address) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => failure = Some(err),
+        }
+    }
 
-                        if stream.set_nonblocking(true).is_err() {
-                            continue;
-                        }
+    Err(failure.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::AddrNotAvailable,
+            format!("{} has no address", host),
+        )
+    }))
+}
+
+/// Runs on the simulation thread: steps the rooms and handles what clients send, until the
+/// network thread hangs up.
+fn simulate(
+    mut rooms: super::super::world::room::RoomManager,
+    mut handler: super::handler::Handler,
+    inputs: crossbeam::channel::Receiver<Input>,
+    outputs: crossbeam::channel::Sender<Output>,
+    waker: std::sync::Arc<mio::Waker>,
+    tick_interval: std::time::Duration,
+    ping_interval: std::time::Duration,
+) -> super::super::world::room::RoomManager {
+    let mut peers = super::peer::Peers::new();
+    let mut next_tick: Option<std::time::Instant> = None;
+    let mut next_heartbeat = std::time::Instant::now() + ping_interval;
 
-                        let mut sockets = sockets.lock().unwrap();
-                        let mut handler = handler.lock().unwrap();
+    loop {
+        // Empty rooms have nothing to simulate and a server without clients has nobody to ping,
+        // so the thread sleeps until the network thread reports something.
+        let wake = match (next_tick, !peers.is_empty()) {
+            (Some(next_tick), true) => Some(std::cmp::min(next_tick, next_heartbeat)),
+            (Some(next_tick), false) => Some(next_tick),
+            (None, true) => Some(next_heartbeat),
+            (None, false) => None,
+        };
+        let input = match wake {
+            Some(wake) => match inputs
+                .recv_timeout(wake.saturating_duration_since(std::time::Instant::now()))
+            {
+                Ok(input) => Some(input),
+                Err(crossbeam::channel::RecvTimeoutError::Timeout) => None,
+                Err(crossbeam::channel::RecvTimeoutError::Disconnected) => break,
+            },
+            None => match inputs.recv() {
+                Ok(input) => Some(input),
+                Err(..) => break,
+            },
+        };
 
-                        let mut socket = super::socket::Socket::from(stream);
+        // Frames are only collected while inputs are pending and handled afterwards, so a
+        // client sending faster than they are handled overflows its peer's inbox.
+        let mut received = std::collections::HashSet::new();
 
-                        handler.add_socket(&mut socket);
-                        sockets.push(socket);
+        for input in input.into_iter().chain(inputs.try_iter()) {
+            match input {
+                Input::Connected { id } => {
+                    let mut peer = super::peer::Peer::new(id, outputs.clone());
 
-                        println!("client income...");
+                    handler.add_socket(&mut peer);
+                    peers.insert(id, peer);
+                }
+                Input::Frame { id, data } => {
+                    let peer = match peers.get_mut(&id) {
+                        Some(peer) => peer,
+                        None => continue,
+                    };
+
+                    if !peer.push(data) {
+                        log::warn!("client {} is flooding the server, disconnecting", id);
+
+                        peer.close();
+                    } else {
+                        received.insert(id);
+                    }
+                }
+                Input::Disconnected { id, resumable } => {
+                    if peers.contains_key(&id) {
+                        handler.handle_socket(id, &mut peers, &mut rooms);
+                        handler.remove_socket(id, resumable, &mut peers, &mut rooms);
+                        peers.remove(&id);
                     }
-                    Err(..) => {}
                 }
             }
-        });
-    })
-    .unwrap();
+        }
+
+        for id in received {
+            handler.handle_socket(id, &mut peers, &mut rooms);
+        }
+
+        let now = std::time::Instant::now();
+
+        match next_tick {
+            Some(tick) if tick <= now => {
+                handler.tick(&mut peers, &mut rooms);
+
+                // Ticks missed while the server was overloaded are skipped rather than replayed.
+                next_tick = Some(std::cmp::max(tick + tick_interval, now));
+            }
+            Some(..) => {}
+            None => next_tick = Some(now + tick_interval),
+        }
+
+        if next_heartbeat <= now {
+            handler.heartbeat(&mut peers, now);
+            next_heartbeat = now + ping_interval;
+        }
+
+        if rooms.player_count() == 0 {
+            next_tick = None;
+        }
+
+        if !outputs.is_empty() {
+            waker.wake().ok();
+        }
+    }
+
+    handler.shutdown(&mut peers);
+    waker.wake().ok();
+
+    rooms
+}
+
+/// Carries out an output of the simulation thread, unless its socket died in the meantime.
+fn deliver(
+    output: Output,
+    sockets: &mut std::collections::HashMap<mio::Token, super::socket::Socket>,
+    tokens: &std::collections::HashMap<u64, mio::Token>,
+) {
+    let id = match output {
+        Output::Send { id, .. } | Output::Offer { id, .. } | Output::Close { id } => id,
+    };
+    let socket = match tokens.get(&id).and_then(|token| sockets.get_mut(token)) {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    match output {
+        Output::Send { data, .. } => socket.send(data),
+        Output::Offer { data, .. } => socket.offer(data),
+        Output::Close { .. } => socket.close(),
+    }
+}
+
+fn accept(
+    registry: &mio::Registry,
+    listener: &mut mio::net::TcpListener,
+    next_token: &mut usize,
+    send_buffer: usize,
+    sockets: &mut std::collections::HashMap<mio::Token, super::socket::Socket>,
+    tokens: &mut std::collections::HashMap<u64, mio::Token>,
+    inputs: &crossbeam::channel::Sender<Input>,
+) {
+    loop {
+        let (mut stream, address) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => return,
+            // Only this connection is affected; the next one may be accepted.
+            Err(ref err)
+                if err.kind() == std::io::ErrorKind::Interrupted
+                    || err.kind() == std::io::ErrorKind::ConnectionAborted =>
+            {
+                continue
+            }
+            // Failures such as running out of file descriptors would repeat right away; they
+            // are retried when the next connection comes in.
+            Err(err) => {
+                log::warn!("failed to accept a connection: {}", err);
+                return;
+            }
+        };
+
+        if let Err(err) = stream.set_nodelay(false) {
+            log::warn!("dropping connection from {}: {}", address, err);
+            continue;
+        }
+
+        let token = mio::Token(*next_token);
+
+        *next_token += 1;
+
+        if let Err(err) = registry.register(
+            &mut stream,
+            token,
+            mio::Interest::READABLE | mio::Interest::WRITABLE,
+        ) {
+            log::warn!("dropping connection from {}: {}", address, err);
+            continue;
+        }
+
+        let socket = super::socket::Socket::from(stream, token, send_buffer);
+
+        log::info!("client {} connected", socket.id());
+
+        inputs.send(Input::Connected { id: socket.id() }).ok();
+        tokens.insert(socket.id(), token);
+        sockets.insert(token, socket);
+    }
 }
@@ -1,122 +1,246 @@
+extern crate byteorder;
+extern crate log;
+extern crate mio;
 extern crate rand;
 
+use super::packet;
+use byteorder::ReadBytesExt;
 use rand::RngCore;
 use std::io::Read;
 use std::io::Write;
 
+/// State of a connection after reading from or writing to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Open,
+    /// The client shut down its side of the connection.
+    PeerClosed,
+    /// The server closed the connection with `Socket::close` and flushed its last packets.
+    Closed,
+    /// The client sent a frame with an invalid length.
+    InvalidFrame,
+    /// Reading from or writing to the stream failed.
+    Failed(std::io::ErrorKind),
+    /// Nothing was received from the client for longer than the idle timeout.
+    TimedOut,
+    /// The client fell so far behind that a packet it could not do without did not fit in its
+    /// send buffer.
+    Overflowed,
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Status::Open => write!(f, "open"),
+            Status::PeerClosed => write!(f, "closed by the client"),
+            Status::Closed => write!(f, "closed by the server"),
+            Status::InvalidFrame => write!(f, "invalid frame"),
+            Status::Failed(kind) => write!(f, "connection error: {}", kind),
+            Status::TimedOut => write!(f, "timed out"),
+            Status::Overflowed => write!(f, "too far behind"),
+        }
+    }
+}
+
+/// Bytes a transmitter queues at most when built with `Transmitter::default`.
+pub const DEFAULT_SEND_BUFFER: usize = 1 << 20;
+
+/// Outgoing byte queue. Packets are appended to a single buffer in the order they are sent, so
+/// that everything pending goes out in as few writes as the stream allows.
 pub struct Transmitter {
+    buffer: Vec<u8>,
     sent: usize,
-    queue: std::collections::VecDeque<Vec<u8>>,
+    capacity: usize,
+}
+
+impl Default for Transmitter {
+    fn default() -> Transmitter {
+        Transmitter::new(DEFAULT_SEND_BUFFER)
+    }
 }
 
 impl Transmitter {
-    pub fn new() -> Transmitter {
+    /// Creates a transmitter holding at most `capacity` unsent bytes.
+    pub fn new(capacity: usize) -> Transmitter {
         Transmitter {
+            buffer: Vec::new(),
             sent: 0,
-            queue: std::collections::VecDeque::new(),
+            capacity,
         }
     }
 
-    pub fn send(&mut self, data: Vec<u8>) {
-        self.queue.push_front(data);
+    /// Queues `data` after everything sent before; returns `false` and drops it if it would not
+    /// fit within the capacity. A packet larger than the capacity is only accepted by an empty
+    /// queue.
+    pub fn send(&mut self, data: Vec<u8>) -> bool {
+        let pending = self.pending();
+
+        if pending > 0 && pending + data.len() > self.capacity {
+            return false;
+        }
+
+        if pending == 0 {
+            self.buffer.clear();
+            self.sent = 0;
+        }
+
+        self.buffer.extend(data);
+        true
     }
 
-    pub fn update(&mut self, stream: &mut std::net::TcpStream) -> bool {
-        while !self.queue.is_empty() {
-            let data = self.queue.front().unwrap();
+    /// Number of queued bytes not written yet.
+    pub fn pending(&self) -> usize {
+        self.buffer.len() - self.sent
+    }
 
-            match stream.write(&data[self.sent..]) {
-                Ok(sent) => {
-                    self.sent += sent;
+    pub fn is_empty(&self) -> bool {
+        self.pending() == 0
+    }
 
-                    if self.sent == data.len() {
-                        self.sent = 0;
-                        self.queue.pop_front();
-                    } else {
-                        break;
-                    }
-                }
-                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    break;
-                }
-                Err(..) => {
-                    return false;
-                }
+    /// Forgets every queued byte.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.sent = 0;
+    }
+
+    /// Writes as much queued data as `stream` accepts.
+    pub fn update<W: Write>(&mut self, stream: &mut W) -> Status {
+        while self.sent < self.buffer.len() {
+            match stream.write(&self.buffer[self.sent..]) {
+                Ok(0) => return Status::Failed(std::io::ErrorKind::WriteZero),
+                Ok(sent) => self.sent += sent,
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Status::Failed(e.kind()),
             }
         }
 
-        true
+        // Written bytes are only dropped once they make up half of the buffer, so that a slow
+        // stream does not cause the remainder to be moved on every write.
+        if self.sent == self.buffer.len() {
+            self.clear();
+        } else if self.sent > self.buffer.len() / 2 {
+            self.buffer.drain(..self.sent);
+            self.sent = 0;
+        }
+
+        Status::Open
     }
 }
 
 pub struct Receiver {
-    received: usize,
-    buffer: Option<Vec<u8>>,
+    received: u64,
+    buffer: Vec<u8>,
+    frames: std::collections::VecDeque<Vec<u8>>,
+}
+
+impl Default for Receiver {
+    fn default() -> Receiver {
+        Receiver::new()
+    }
 }
 
 impl Receiver {
     pub fn new() -> Receiver {
         Receiver {
             received: 0,
-            buffer: None,
+            buffer: Vec::new(),
+            frames: std::collections::VecDeque::new(),
         }
     }
 
     pub fn retrieve(&mut self) -> Option<Vec<u8>> {
-        match &self.buffer {
-            Some(buffer) => {
-                if self.received == buffer.len() {
-                    self.received = 0;
-                    self.buffer.take()
-                } else {
-                    None
+        self.frames.pop_front()
+    }
+
+    /// Number of bytes read from the stream so far.
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Reads everything `stream` has to offer and splits it into frames. Frames received before
+    /// the client shut down its side are still retrievable when `PeerClosed` is returned.
+    pub fn update<R: Read>(&mut self, stream: &mut R) -> Status {
+        let mut chunk = [0u8; 4096];
+        let mut status = Status::Open;
+
+        loop {
+            match stream.read(&mut chunk) {
+                Ok(0) => {
+                    status = Status::PeerClosed;
+                    break;
+                }
+                Ok(received) => {
+                    self.received += received as u64;
+                    self.buffer.extend_from_slice(&chunk[..received]);
                 }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Status::Failed(e.kind()),
             }
-            None => None,
         }
-    }
 
-    pub fn receive(&mut self, length: usize) {
-        self.received = 0;
-        self.buffer = Some(vec![0; length]);
+        if !self.split_frames() {
+            return Status::InvalidFrame;
+        }
+
+        status
     }
 
-    pub fn update(&mut self, stream: &mut std::net::TcpStream) -> bool {
-        match &mut self.buffer {
-            Some(buffer) => {
-                if self.received == buffer.len() {
-                    true
-                } else {
-                    match stream.read(&mut buffer[self.received..]) {
-                        Ok(received) => {
-                            self.received += received;
-                            true
-                        }
-                        Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => true,
-                        Err(..) => false,
-                    }
-                }
+    /// Moves every complete frame out of the buffer, rejecting frames with an invalid length.
+    fn split_frames(&mut self) -> bool {
+        let mut offset = 0;
+
+        while self.buffer.len() - offset >= packet::FRAME_HEADER_SIZE {
+            let length = (&self.buffer[offset..])
+                .read_u16::<byteorder::LittleEndian>()
+                .unwrap() as usize;
+
+            if length == 0 || length > packet::MAX_FRAME_SIZE {
+                return false;
+            }
+
+            let start = offset + packet::FRAME_HEADER_SIZE;
+
+            if self.buffer.len() - start < length {
+                break;
             }
-            None => true,
+
+            self.frames
+                .push_back(self.buffer[start..start + length].to_vec());
+            offset = start + length;
         }
+
+        self.buffer.drain(..offset);
+
+        true
     }
 }
 
 pub struct Socket {
     id: u64,
-    stream: std::net::TcpStream,
+    token: mio::Token,
+    closing: bool,
+    overflowed: bool,
+    last_seen: std::time::Instant,
+    stream: mio::net::TcpStream,
     tx: Transmitter,
     rx: Receiver,
 }
 
 impl Socket {
-    pub fn from(stream: std::net::TcpStream) -> Socket {
+    /// Wraps an accepted stream, queueing at most `send_buffer` bytes for it.
+    pub fn from(stream: mio::net::TcpStream, token: mio::Token, send_buffer: usize) -> Socket {
         let mut rng = rand::prelude::thread_rng();
 
         Socket {
             id: rng.next_u64(),
+            token,
+            closing: false,
+            overflowed: false,
+            last_seen: std::time::Instant::now(),
             stream,
-            tx: Transmitter::new(),
+            tx: Transmitter::new(send_buffer),
             rx: Receiver::new(),
         }
     }
@@ -125,23 +249,344 @@ impl Socket {
         self.id
     }
 
-    pub fn stream(&mut self) -> &mut std::net::TcpStream {
+    pub fn token(&self) -> mio::Token {
+        self.token
+    }
+
+    /// When the client last sent anything, or connected.
+    pub fn last_seen(&self) -> std::time::Instant {
+        self.last_seen
+    }
+
+    pub fn stream(&mut self) -> &mut mio::net::TcpStream {
         &mut self.stream
     }
 
+    /// Queues a packet the client cannot do without. A client too slow to make room for it is
+    /// disconnected, dropping whatever was still queued.
     pub fn send(&mut self, data: Vec<u8>) {
-        self.tx.send(data);
+        if self.closing || self.tx.send(data) {
+            return;
+        }
+
+        log::warn!(
+            "client {} is not keeping up with {} bytes pending, disconnecting",
+            self.id,
+            self.tx.pending()
+        );
+
+        self.tx.clear();
+        self.closing = true;
+        self.overflowed = true;
     }
 
-    pub fn receive(&mut self, length: usize) {
-        self.rx.receive(length);
+    /// Queues a packet the client can do without, such as a chat message; it is dropped if the
+    /// client is too slow to make room for it.
+    pub fn offer(&mut self, data: Vec<u8>) {
+        if !self.closing && !self.tx.send(data) {
+            log::debug!("client {} is lagging, dropping a packet", self.id);
+        }
     }
 
     pub fn retrieve(&mut self) -> Option<Vec<u8>> {
         self.rx.retrieve()
     }
 
-    pub fn update(&mut self) -> bool {
-        self.tx.update(&mut self.stream) && self.rx.update(&mut self.stream)
+    /// Stops accepting packets from and for the peer; the socket reports itself dead once the
+    /// packets queued so far have been flushed.
+    pub fn close(&mut self) {
+        self.closing = true;
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.tx.is_empty()
+    }
+
+    /// Writes as much queued data as the stream accepts without reading anything. Reports
+    /// `Closed` once a closing socket has nothing left to send, and `Overflowed` once `send`
+    /// gave up on the client.
+    pub fn flush(&mut self) -> Status {
+        if self.overflowed {
+            return Status::Overflowed;
+        }
+
+        match self.tx.update(&mut self.stream) {
+            Status::Open if self.closing && self.tx.is_empty() => Status::Closed,
+            status => status,
+        }
+    }
+
+    pub fn update(&mut self) -> Status {
+        if self.closing {
+            return self.flush();
+        }
+
+        match self.tx.update(&mut self.stream) {
+            Status::Open => {}
+            status => return status,
+        }
+
+        let received = self.rx.received();
+        let status = self.rx.update(&mut self.stream);
+
+        if self.rx.received() != received {
+            self.last_seen = std::time::Instant::now();
+        }
+
+        status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stream taking at most `chunk` bytes per write, and `budget` bytes in total before it
+    /// would block.
+    struct Partial {
+        written: Vec<u8>,
+        writes: usize,
+        chunk: usize,
+        budget: usize,
+    }
+
+    impl Partial {
+        fn new(chunk: usize, budget: usize) -> Partial {
+            Partial {
+                written: Vec::new(),
+                writes: 0,
+                chunk,
+                budget,
+            }
+        }
+    }
+
+    impl Write for Partial {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            if self.budget == 0 {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+
+            let length = std::cmp::min(data.len(), std::cmp::min(self.chunk, self.budget));
+
+            self.written.extend_from_slice(&data[..length]);
+            self.writes += 1;
+            self.budget -= length;
+
+            Ok(length)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Stream yielding `data` and then blocking, as a client that keeps its connection open.
+    struct Idle {
+        data: Vec<u8>,
+    }
+
+    impl Read for Idle {
+        fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+            if self.data.is_empty() {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+
+            let length = std::cmp::min(buffer.len(), self.data.len());
+
+            buffer[..length].copy_from_slice(&self.data[..length]);
+            self.data.drain(..length);
+
+            Ok(length)
+        }
+    }
+
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::ConnectionReset.into())
+        }
+    }
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn packets_are_written_in_order() {
+        let mut tx = Transmitter::default();
+        let mut stream = Partial::new(2, usize::MAX);
+
+        assert!(tx.send(vec![1, 2, 3]));
+        assert!(tx.send(vec![4, 5]));
+        assert!(tx.send(vec![6]));
+        assert_eq!(tx.update(&mut stream), Status::Open);
+
+        assert_eq!(stream.written, vec![1, 2, 3, 4, 5, 6]);
+        assert!(tx.is_empty());
+    }
+
+    #[test]
+    fn partial_writes_resume_where_they_stopped() {
+        let mut tx = Transmitter::default();
+        let mut stream = Partial::new(usize::MAX, 3);
+
+        tx.send(vec![1, 2, 3, 4]);
+        tx.send(vec![5, 6]);
+        assert_eq!(tx.update(&mut stream), Status::Open);
+
+        assert_eq!(stream.written, vec![1, 2, 3]);
+        assert_eq!(tx.pending(), 3);
+
+        tx.send(vec![7]);
+        stream.budget = 1;
+        assert_eq!(tx.update(&mut stream), Status::Open);
+        stream.budget = usize::MAX;
+        assert_eq!(tx.update(&mut stream), Status::Open);
+
+        assert_eq!(stream.written, vec![1, 2, 3, 4, 5, 6, 7]);
+        assert!(tx.is_empty());
+    }
+
+    #[test]
+    fn pending_packets_are_coalesced() {
+        let mut tx = Transmitter::default();
+        let mut stream = Partial::new(usize::MAX, usize::MAX);
+
+        for packet in 0..10 {
+            tx.send(vec![packet; 3]);
+        }
+
+        assert_eq!(tx.update(&mut stream), Status::Open);
+        assert_eq!(stream.writes, 1);
+        assert_eq!(stream.written.len(), 30);
+    }
+
+    #[test]
+    fn capacity_bounds_pending_bytes() {
+        let mut tx = Transmitter::new(4);
+        let mut stream = Partial::new(usize::MAX, 2);
+
+        assert!(tx.send(vec![1, 2, 3]));
+        assert!(!tx.send(vec![4, 5]));
+        assert_eq!(tx.update(&mut stream), Status::Open);
+        assert!(tx.send(vec![4, 5]));
+        assert!(!tx.send(vec![6, 7]));
+
+        stream.budget = usize::MAX;
+        assert_eq!(tx.update(&mut stream), Status::Open);
+        assert_eq!(stream.written, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn oversized_packet_only_fits_an_empty_queue() {
+        let mut tx = Transmitter::new(2);
+
+        assert!(tx.send(vec![0; 5]));
+        assert!(!tx.send(vec![0]));
+        assert_eq!(tx.pending(), 5);
+    }
+
+    #[test]
+    fn stream_errors_are_reported() {
+        let mut tx = Transmitter::default();
+
+        tx.send(vec![1]);
+
+        assert_eq!(
+            tx.update(&mut Broken),
+            Status::Failed(std::io::ErrorKind::BrokenPipe)
+        );
+    }
+
+    #[test]
+    fn receiver_keeps_open_connections() {
+        let mut rx = Receiver::new();
+        let mut stream = Idle {
+            data: vec![2, 0, 4, 0, 2, 0],
+        };
+
+        assert_eq!(rx.update(&mut stream), Status::Open);
+        assert_eq!(rx.retrieve(), Some(vec![4, 0]));
+        assert_eq!(rx.retrieve(), None);
+    }
+
+    #[test]
+    fn receiver_detects_orderly_shutdown() {
+        let mut rx = Receiver::new();
+        let data = [packet::frame(&[8, 0]), packet::frame(&[4, 0])].concat();
+
+        assert_eq!(rx.update(&mut &data[..]), Status::PeerClosed);
+        assert_eq!(rx.retrieve(), Some(vec![8, 0]));
+        assert_eq!(rx.retrieve(), Some(vec![4, 0]));
+    }
+
+    #[test]
+    fn receiver_reports_invalid_frames_and_errors() {
+        assert_eq!(
+            Receiver::new().update(&mut &[0u8, 0][..]),
+            Status::InvalidFrame
+        );
+        assert_eq!(
+            Receiver::new().update(&mut Broken),
+            Status::Failed(std::io::ErrorKind::ConnectionReset)
+        );
+    }
+
+    /// A socket on one end of a loopback connection, with the other end.
+    fn connected(send_buffer: usize) -> (Socket, std::net::TcpStream) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        stream.set_nonblocking(true).unwrap();
+
+        let socket = Socket::from(
+            mio::net::TcpStream::from_std(stream),
+            mio::Token(0),
+            send_buffer,
+        );
+
+        (socket, client)
+    }
+
+    #[test]
+    fn closing_socket_flushes_before_reporting_closed() {
+        let (mut socket, _client) = connected(16);
+
+        socket.send(vec![1, 2]);
+        socket.close();
+        socket.send(vec![3]);
+
+        assert!(socket.has_pending());
+        assert_eq!(socket.flush(), Status::Closed);
+        assert!(!socket.has_pending());
+    }
+
+    #[test]
+    fn overflowing_send_reports_the_client_too_far_behind() {
+        let (mut socket, _client) = connected(4);
+
+        socket.send(vec![1, 2, 3]);
+        socket.offer(vec![4, 5]);
+        assert!(!socket.is_closing());
+
+        socket.send(vec![6, 7]);
+
+        assert!(socket.is_closing());
+        assert!(!socket.has_pending());
+        assert_eq!(socket.flush(), Status::Overflowed);
+        assert_eq!(socket.update(), Status::Overflowed);
     }
 }
@@ -0,0 +1,182 @@
+use super::map::Map;
+
+/// Labels every walkable block with the index of the 4-connected region it belongs to, in
+/// row-major order; other blocks are labelled `None`.
+pub fn regions<F>(map: &Map, walkable: F) -> Vec<Option<usize>>
+where
+    F: Fn(u8) -> bool,
+{
+    let mut labels = vec![None; map.data().len()];
+    let mut count = 0;
+
+    for start in 0..labels.len() {
+        if labels[start].is_some() || !walkable(map.data()[start]) {
+            continue;
+        }
+
+        let mut queue = std::collections::VecDeque::new();
+
+        labels[start] = Some(count);
+        queue.push_back(start);
+
+        while let Some(index) = queue.pop_front() {
+            for (_, neighbor) in map.neighbor_indices(index) {
+                if labels[neighbor].is_none() && walkable(map.data()[neighbor]) {
+                    labels[neighbor] = Some(count);
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+
+        count += 1;
+    }
+
+    labels
+}
+
+/// Number of moves on the shortest walkable path from `from` to `to`, or `None` if `to` cannot be
+/// reached.
+pub fn shortest_path<F>(map: &Map, from: (i32, i32), to: (i32, i32), walkable: F) -> Option<usize>
+where
+    F: Fn(u8) -> bool,
+{
+    super::pathfinding::find_path(map, from, to, walkable, &super::pathfinding::Manhattan)
+        .map(|path| path.len() - 1)
+}
+
+/// Joins every region to the one containing `origin` by turning the blocks along the shortest
+/// corridor between them into `floor`, which must be walkable. Blocks on the edge of the map are
+/// never carved. Returns the number of blocks changed.
+pub fn carve<F>(map: &mut Map, origin: (i32, i32), floor: u8, walkable: F) -> usize
+where
+    F: Fn(u8) -> bool,
+{
+    let origin = match map.index(origin.0, origin.1) {
+        Some(origin) => origin,
+        None => return 0,
+    };
+    let mut carved = 0;
+
+    loop {
+        let labels = regions(map, &walkable);
+        let main = match labels[origin] {
+            Some(main) => main,
+            None => return carved,
+        };
+
+        let mut previous = vec![None; labels.len()];
+        let mut visited = vec![false; labels.len()];
+        let mut queue = std::collections::VecDeque::new();
+        let mut target = None;
+
+        for (index, label) in labels.iter().enumerate() {
+            if *label == Some(main) {
+                visited[index] = true;
+                queue.push_back(index);
+            }
+        }
+
+        while let Some(index) = queue.pop_front() {
+            if labels[index].is_some_and(|label| label != main) {
+                target = Some(index);
+                break;
+            }
+
+            for (_, neighbor) in map.neighbor_indices(index) {
+                if visited[neighbor] || (labels[neighbor].is_none() && is_edge(map, neighbor)) {
+                    continue;
+                }
+
+                visited[neighbor] = true;
+                previous[neighbor] = Some(index);
+                queue.push_back(neighbor);
+            }
+        }
+
+        // Every remaining region is either already joined or only reachable through the edge.
+        let mut index = match target {
+            Some(index) => index,
+            None => return carved,
+        };
+
+        while let Some(next) = previous[index] {
+            if !walkable(map.data()[index]) {
+                let (x, y) = map.position(index);
+
+                map.set_block(x, y, floor);
+                carved += 1;
+            }
+
+            index = next;
+        }
+    }
+}
+
+fn is_edge(map: &Map, index: usize) -> bool {
+    let (x, y) = map.position(index);
+
+    x == 0 || y == 0 || x as u32 == map.width() - 1 || y as u32 == map.height() - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::map::is_floor;
+    use super::*;
+
+    #[test]
+    fn regions_are_labelled_in_row_major_order() {
+        let map = Map::parse(&["..#.", "###.", ".#.."]);
+        let labels = regions(&map, is_floor);
+
+        assert_eq!(
+            labels,
+            vec![
+                Some(0),
+                Some(0),
+                None,
+                Some(1),
+                None,
+                None,
+                None,
+                Some(1),
+                Some(2),
+                None,
+                Some(1),
+                Some(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn sealed_pocket_is_carved() {
+        let mut map = Map::parse(&["#######", "#..#..#", "#..#..#", "#######"]);
+
+        assert_eq!(shortest_path(&map, (1, 1), (5, 2), is_floor), None);
+        assert_eq!(carve(&mut map, (1, 1), 0, is_floor), 1);
+        assert_eq!(shortest_path(&map, (1, 1), (5, 2), is_floor), Some(5));
+        assert_eq!(map.data()[..7], [1; 7]);
+        assert!(regions(&map, is_floor)
+            .iter()
+            .all(|label| *label != Some(1)));
+    }
+
+    #[test]
+    fn border_is_never_carved() {
+        // The pocket in the corner is walled off by the border alone.
+        let mut map = Map::parse(&[".#....", "##....", "......"]);
+
+        assert_eq!(carve(&mut map, (3, 1), 0, is_floor), 0);
+        assert_eq!(map.get(0, 0), Some(0));
+        assert_eq!(map.get(1, 0), Some(1));
+        assert_eq!(map.get(0, 1), Some(1));
+        assert_eq!(shortest_path(&map, (3, 1), (0, 0), is_floor), None);
+    }
+
+    #[test]
+    fn carving_from_outside_the_map_does_nothing() {
+        let mut map = Map::parse(&["#.#", "#.#"]);
+
+        assert_eq!(carve(&mut map, (-1, 0), 0, is_floor), 0);
+        assert_eq!(carve(&mut map, (0, 0), 0, is_floor), 0);
+    }
+}
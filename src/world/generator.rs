@@ -1,9 +1,268 @@
+extern crate log;
 extern crate ordered_float;
 extern crate rand;
 extern crate rand_distr;
 
+use super::object::Direction;
 use rand::distributions::Distribution;
 
+/// Number of times `Generator::generate` restarts after a contradiction before giving up.
+pub const DEFAULT_MAX_ATTEMPTS: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GenerateError {
+    /// No tile has been added to the generator.
+    NoTiles,
+    /// The adjacency rules were built for a different number of tiles.
+    InvalidRules,
+    /// The sample grid or the requested output is smaller than the pattern size, or the sample
+    /// does not match its dimensions.
+    InvalidSample,
+    /// The initial constraints do not cover every cell and tile, or leave a cell with no tile.
+    InvalidInitialConstraints,
+    /// Every attempt ran into a cell with no possible tile; the last such cell is reported.
+    Contradiction { attempts: usize, x: usize, y: usize },
+    /// Every generated map left the exit unreachable from the spawn.
+    Disconnected { attempts: usize },
+}
+
+impl std::fmt::Display for GenerateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GenerateError::NoTiles => write!(f, "no tiles to generate from"),
+            GenerateError::InvalidRules => write!(f, "adjacency rules do not match the tiles"),
+            GenerateError::InvalidSample => write!(f, "sample is too small for its pattern size"),
+            GenerateError::InvalidInitialConstraints => write!(f, "invalid initial constraints"),
+            GenerateError::Contradiction { attempts, x, y } => write!(
+                f,
+                "gave up after {} attempts, the last one contradicting at ({}, {})",
+                attempts, x, y
+            ),
+            GenerateError::Disconnected { attempts } => write!(
+                f,
+                "the exit was unreachable from the spawn in all {} generated maps",
+                attempts
+            ),
+        }
+    }
+}
+
+/// Per-direction adjacency rules between tile indices, as passed to `Generator::generate`.
+pub struct Rules {
+    tile_count: usize,
+    allowed: Vec<Vec<Vec<bool>>>,
+}
+
+impl Rules {
+    /// Creates rules for `tile_count` tiles where no tile may touch any other.
+    pub fn new(tile_count: usize) -> Rules {
+        Rules {
+            tile_count,
+            allowed: vec![vec![vec![false; tile_count]; tile_count]; Direction::ALL.len()],
+        }
+    }
+
+    /// Builds rules from undirected neighbour lists, allowing each pair in all four directions.
+    pub fn from_neighbors(neighbors: &[Vec<usize>]) -> Rules {
+        let mut rules = Rules::new(neighbors.len());
+
+        for (tile, neighbors) in neighbors.iter().enumerate() {
+            for &neighbor in neighbors.iter() {
+                rules.allow_all(tile, neighbor);
+            }
+        }
+
+        rules
+    }
+
+    pub fn tile_count(&self) -> usize {
+        self.tile_count
+    }
+
+    /// Allows `neighbor` to be placed on the `direction` side of `tile`. The inverse rule
+    /// (`tile` on the opposite side of `neighbor`) is derived automatically.
+    pub fn allow(&mut self, tile: usize, direction: Direction, neighbor: usize) {
+        self.allowed[direction.to_u8() as usize][tile][neighbor] = true;
+        self.allowed[direction.opposite().to_u8() as usize][neighbor][tile] = true;
+    }
+
+    /// Allows `tile` and `neighbor` to touch on every side.
+    pub fn allow_all(&mut self, tile: usize, neighbor: usize) {
+        for direction in Direction::ALL.iter() {
+            self.allow(tile, *direction, neighbor);
+        }
+    }
+
+    pub fn allows(&self, tile: usize, direction: Direction, neighbor: usize) -> bool {
+        self.allowed[direction.to_u8() as usize][tile][neighbor]
+    }
+}
+
+/// Tile set learned from a sample grid with the overlapping model: every `size`×`size` window of
+/// the sample becomes a pattern weighted by how often it occurs, and two patterns may be placed
+/// next to each other wherever they agree on their overlap. The patterns are collapsed on a grid
+/// `size - 1` cells narrower and shorter than the output, so that every block of the output is
+/// covered by a whole pattern.
+pub struct Overlapping {
+    size: usize,
+    patterns: Vec<Vec<u8>>,
+    weights: Vec<f32>,
+    max_attempts: usize,
+}
+
+impl Overlapping {
+    pub fn learn(
+        sample: &[u8],
+        width: usize,
+        height: usize,
+        size: usize,
+    ) -> Result<Overlapping, GenerateError> {
+        if size == 0 || width < size || height < size || sample.len() != width * height {
+            return Err(GenerateError::InvalidSample);
+        }
+
+        let mut indices: std::collections::HashMap<Vec<u8>, usize> =
+            std::collections::HashMap::new();
+        let mut overlapping = Overlapping {
+            size,
+            patterns: Vec::new(),
+            weights: Vec::new(),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        };
+
+        for y in 0..=height - size {
+            for x in 0..=width - size {
+                let mut pattern = Vec::with_capacity(size * size);
+
+                for dy in 0..size {
+                    pattern.extend_from_slice(&sample[x + (y + dy) * width..][..size]);
+                }
+
+                match indices.get(&pattern) {
+                    Some(&index) => overlapping.weights[index] += 1f32,
+                    None => {
+                        indices.insert(pattern.clone(), overlapping.patterns.len());
+                        overlapping.patterns.push(pattern);
+                        overlapping.weights.push(1f32);
+                    }
+                }
+            }
+        }
+
+        Ok(overlapping)
+    }
+
+    pub fn pattern_count(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn set_max_attempts(&mut self, max_attempts: usize) {
+        self.max_attempts = std::cmp::max(max_attempts, 1);
+    }
+
+    /// Generates a `width`×`height` map of block ids in row-major order. Each `(x, y, id)` in
+    /// `pins` forces block `id` at that position of the output.
+    pub fn generate<R>(
+        &self,
+        rng: &mut R,
+        width: usize,
+        height: usize,
+        pins: &[(usize, usize, u8)],
+    ) -> Result<Vec<u8>, GenerateError>
+    where
+        R: rand::Rng,
+    {
+        if width < self.size || height < self.size {
+            return Err(GenerateError::InvalidSample);
+        }
+
+        let (mut generator, rules) = self.build();
+        let grid_width = width - self.size + 1;
+        let grid_height = height - self.size + 1;
+        let mut initials = vec![vec![true; self.patterns.len()]; grid_width * grid_height];
+
+        for &(x, y, id) in pins.iter() {
+            if x >= width || y >= height {
+                return Err(GenerateError::InvalidInitialConstraints);
+            }
+
+            let (cell, offset) = self.locate(x, y, grid_width, grid_height);
+
+            for (possibility, pattern) in initials[cell].iter_mut().zip(self.patterns.iter()) {
+                *possibility &= pattern[offset] == id;
+            }
+        }
+
+        generator.set_max_attempts(self.max_attempts);
+
+        let cells = generator.collapse(rng, grid_width, grid_height, &rules, Some(initials))?;
+        let mut result = Vec::with_capacity(width * height);
+
+        for y in 0..height {
+            for x in 0..width {
+                let (cell, offset) = self.locate(x, y, grid_width, grid_height);
+
+                result.push(self.patterns[cells[cell]][offset]);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// The grid cell whose pattern provides output block `(x, y)`, and the block's index within
+    /// that pattern. Blocks past the grid's last row or column come from the inside of the
+    /// patterns along that edge.
+    fn locate(&self, x: usize, y: usize, grid_width: usize, grid_height: usize) -> (usize, usize) {
+        let cell_x = std::cmp::min(x, grid_width - 1);
+        let cell_y = std::cmp::min(y, grid_height - 1);
+
+        (
+            cell_x + cell_y * grid_width,
+            (x - cell_x) + (y - cell_y) * self.size,
+        )
+    }
+
+    /// Builds a generator whose tiles are the learned patterns, along with their adjacency rules.
+    fn build(&self) -> (Generator, Rules) {
+        let mut generator = Generator::new();
+        let mut rules = Rules::new(self.patterns.len());
+
+        for (pattern, weight) in self.patterns.iter().zip(self.weights.iter()) {
+            generator.add_tile(pattern[0], *weight);
+        }
+
+        for tile in 0..self.patterns.len() {
+            for neighbor in 0..self.patterns.len() {
+                for direction in Direction::ALL.iter() {
+                    if self.agrees(tile, *direction, neighbor) {
+                        rules.allow(tile, *direction, neighbor);
+                    }
+                }
+            }
+        }
+
+        (generator, rules)
+    }
+
+    /// Whether `neighbor`, shifted one block towards `direction`, matches `tile` where they overlap.
+    fn agrees(&self, tile: usize, direction: Direction, neighbor: usize) -> bool {
+        let (dx, dy) = direction.offset();
+        let size = self.size as i32;
+
+        for y in std::cmp::max(0, dy)..std::cmp::min(size, size + dy) {
+            for x in std::cmp::max(0, dx)..std::cmp::min(size, size + dx) {
+                if self.patterns[tile][(x + y * size) as usize]
+                    != self.patterns[neighbor][(x - dx + (y - dy) * size) as usize]
+                {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+}
+
 pub struct Tile {
     id: u8,
     weight: f32,
@@ -11,6 +270,7 @@ pub struct Tile {
 
 pub struct Generator {
     tiles: Vec<Tile>,
+    max_attempts: usize,
     waves: Vec<Vec<bool>>,
     sum_one: Vec<usize>,
     sum_weight: Vec<f32>,
@@ -18,10 +278,17 @@ pub struct Generator {
     entropies: Vec<f32>,
 }
 
+impl Default for Generator {
+    fn default() -> Generator {
+        Generator::new()
+    }
+}
+
 impl Generator {
     pub fn new() -> Generator {
         Generator {
             tiles: Vec::new(),
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
             waves: Vec::new(),
             sum_one: Vec::new(),
             sum_weight: Vec::new(),
@@ -34,20 +301,107 @@ impl Generator {
         self.tiles.push(Tile { id, weight });
     }
 
-    pub fn generate(
+    /// The block id each tile index produces.
+    pub fn tile_ids(&self) -> Vec<u8> {
+        self.tiles.iter().map(|tile| tile.id).collect()
+    }
+
+    pub fn set_max_attempts(&mut self, max_attempts: usize) {
+        self.max_attempts = std::cmp::max(max_attempts, 1);
+    }
+
+    pub fn generate<R>(
         &mut self,
+        rng: &mut R,
         width: usize,
         height: usize,
-        constraints: &std::collections::HashMap<usize, Vec<usize>>,
+        rules: &Rules,
         initial_constraints: Option<Vec<Vec<bool>>>,
-    ) -> Option<Vec<u8>> {
-        if self.tiles.len() == 0 {
-            return None;
+    ) -> Result<Vec<u8>, GenerateError>
+    where
+        R: rand::Rng,
+    {
+        let cells = self.collapse(rng, width, height, rules, initial_constraints)?;
+
+        Ok(cells.iter().map(|&tile| self.tiles[tile].id).collect())
+    }
+
+    /// Like `generate`, but returns the tile index chosen for every cell instead of its block id.
+    pub fn collapse<R>(
+        &mut self,
+        rng: &mut R,
+        width: usize,
+        height: usize,
+        rules: &Rules,
+        initial_constraints: Option<Vec<Vec<bool>>>,
+    ) -> Result<Vec<usize>, GenerateError>
+    where
+        R: rand::Rng,
+    {
+        if self.tiles.is_empty() {
+            return Err(GenerateError::NoTiles);
+        }
+
+        if rules.tile_count() != self.tiles.len() {
+            return Err(GenerateError::InvalidRules);
+        }
+
+        if let Some(initial) = &initial_constraints {
+            if initial.len() != width * height
+                || initial.iter().any(|wave| {
+                    wave.len() != self.tiles.len() || wave.iter().all(|&possibility| !possibility)
+                })
+            {
+                return Err(GenerateError::InvalidInitialConstraints);
+            }
         }
 
+        let mut contradiction = 0;
+
+        for attempt in 0..self.max_attempts {
+            match self.attempt(rng, width, height, rules, &initial_constraints) {
+                Ok(result) => return Ok(result),
+                Err(index) => {
+                    log::debug!(
+                        "generation attempt {} contradicted at ({}, {})",
+                        attempt + 1,
+                        index % width,
+                        index / width
+                    );
+                    contradiction = index;
+                }
+            }
+        }
+
+        Err(GenerateError::Contradiction {
+            attempts: self.max_attempts,
+            x: contradiction % width,
+            y: contradiction / width,
+        })
+    }
+
+    /// Runs one collapse from scratch, returning the index of the cell that ran out of
+    /// possibilities if propagation hits a contradiction.
+    fn attempt<R>(
+        &mut self,
+        rng: &mut R,
+        width: usize,
+        height: usize,
+        rules: &Rules,
+        initial_constraints: &Option<Vec<Vec<bool>>>,
+    ) -> Result<Vec<usize>, usize>
+    where
+        R: rand::Rng,
+    {
+        self.waves.clear();
+        self.sum_one.clear();
+        self.sum_weight.clear();
+        self.sum_weight_log_weight.clear();
+        self.entropies.clear();
+
         match initial_constraints {
             Some(initial) => {
-                self.waves = initial;
+                self.waves = initial.clone();
 
                 for wave in self.waves.iter() {
                     self.sum_one
@@ -95,180 +449,156 @@ impl Generator {
             }
         }
 
-        let mut rng = rand::thread_rng();
         let mut min_heap: std::collections::BTreeMap<ordered_float::OrderedFloat<f32>, Vec<usize>> =
             std::collections::BTreeMap::new();
 
-        for index in 0..self.entropies.len() {
-            match min_heap.get_mut(&ordered_float::OrderedFloat::from(self.entropies[index])) {
-                Some(indices) => indices.push(index),
-                None => {
-                    min_heap.insert(
-                        ordered_float::OrderedFloat::from(self.entropies[index]),
-                        vec![index],
-                    );
-                }
-            }
+        for (index, entropy) in self.entropies.iter().enumerate() {
+            min_heap
+                .entry(ordered_float::OrderedFloat::from(*entropy))
+                .or_default()
+                .push(index);
         }
 
-        let mut tiles: Vec<bool> = vec![false; self.tiles.len()];
-        let mut stack: Vec<usize> = Vec::new();
-        let mut visited_set: std::collections::HashSet<usize> = std::collections::HashSet::new();
+        let mut tiles: Vec<Vec<bool>> = vec![vec![false; self.tiles.len()]; Direction::ALL.len()];
+        // Cells narrowed by the initial constraints are propagated before anything is observed.
+        let mut stack: Vec<usize> = (0..self.waves.len())
+            .filter(|&index| self.sum_one[index] < self.tiles.len())
+            .collect();
 
         loop {
-            let mut entropy: Option<ordered_float::OrderedFloat<f32>> = None;
-
-            {
-                if min_heap.len() == 0 {
-                    break;
-                }
+            if stack.is_empty() {
+                let mut entropy: Option<ordered_float::OrderedFloat<f32>> = None;
 
-                let minimum = min_heap.iter_mut().next().unwrap();
-                let index = *minimum.1.first().unwrap();
-                let mut choices = self.waves[index]
-                    .iter()
-                    .enumerate()
-                    .filter(|&(_, possibility)| *possibility)
-                    .map(|(index, _)| (self.tiles[index].weight, index))
-                    .collect::<Vec<(f32, usize)>>();
-
-                for index in 1..choices.len() {
-                    choices[index].0 += choices[index - 1].0;
-                }
-
-                let pivot = rand::distributions::Uniform::new(0f32, 1f32).sample(&mut rng)
-                    * choices.last().unwrap().0;
-
-                let mut choice = choices.last().unwrap().1;
-
-                for index in 0..choices.len() - 1 {
-                    if pivot <= choices[index].0 {
-                        choice = choices[index].1;
+                {
+                    if min_heap.is_empty() {
                         break;
                     }
-                }
 
-                for possibility in self.waves[index].iter_mut() {
-                    *possibility = false;
-                }
+                    let minimum = min_heap.iter_mut().next().unwrap();
+                    let index = *minimum.1.first().unwrap();
+                    let mut choices = self.waves[index]
+                        .iter()
+                        .enumerate()
+                        .filter(|&(_, possibility)| *possibility)
+                        .map(|(index, _)| (self.tiles[index].weight, index))
+                        .collect::<Vec<(f32, usize)>>();
+
+                    for index in 1..choices.len() {
+                        let previous = choices[index - 1].0;
+                        choices[index].0 += previous;
+                    }
 
-                self.waves[index][choice] = true;
+                    let pivot = rand::distributions::Uniform::new(0f32, 1f32).sample(rng)
+                        * choices.last().unwrap().0;
 
-                self.sum_one[index] = 0;
-                self.sum_weight[index] = 0f32;
-                self.sum_weight_log_weight[index] = 0f32;
-                self.entropies[index] = f32::NAN;
+                    let mut choice = choices.last().unwrap().1;
 
-                minimum.1.remove(
-                    minimum
-                        .1
-                        .iter()
-                        .position(|wave_index| *wave_index == index)
-                        .unwrap(),
-                );
+                    for &(weight, index) in choices.iter().take(choices.len() - 1) {
+                        if pivot <= weight {
+                            choice = index;
+                            break;
+                        }
+                    }
 
-                stack.push(index);
+                    for possibility in self.waves[index].iter_mut() {
+                        *possibility = false;
+                    }
 
-                if minimum.1.len() == 0 {
-                    entropy = Some(*minimum.0);
-                }
-            }
+                    self.waves[index][choice] = true;
 
-            if entropy.is_some() {
-                min_heap.remove(&entropy.unwrap());
-            }
+                    self.sum_one[index] = 0;
+                    self.sum_weight[index] = 0f32;
+                    self.sum_weight_log_weight[index] = 0f32;
+                    self.entropies[index] = f32::NAN;
 
-            while !stack.is_empty() {
-                let index = stack.pop().unwrap();
+                    minimum.1.remove(
+                        minimum
+                            .1
+                            .iter()
+                            .position(|wave_index| *wave_index == index)
+                            .unwrap(),
+                    );
 
-                if visited_set.contains(&index) {
-                    continue;
-                }
+                    stack.push(index);
 
-                visited_set.insert(index);
+                    if minimum.1.is_empty() {
+                        entropy = Some(*minimum.0);
+                    }
+                }
 
-                for tile in tiles.iter_mut() {
-                    *tile = false;
+                if let Some(entropy) = entropy {
+                    min_heap.remove(&entropy);
                 }
+            }
 
-                for tile_index in 0..self.tiles.len() {
-                    if !self.waves[index][tile_index] {
-                        continue;
-                    }
+            while let Some(index) = stack.pop() {
+                for direction in Direction::ALL.iter() {
+                    let tiles = &mut tiles[direction.to_u8() as usize];
 
-                    for possible_neighbor_tile_index in constraints[&tile_index].iter() {
-                        tiles[*possible_neighbor_tile_index] = true;
+                    for neighbor_tile_index in 0..self.tiles.len() {
+                        tiles[neighbor_tile_index] = (0..self.tiles.len()).any(|tile_index| {
+                            self.waves[index][tile_index]
+                                && rules.allows(tile_index, *direction, neighbor_tile_index)
+                        });
                     }
                 }
 
-                let mut handle_tile = |x: usize, y: usize| {
+                let mut contradiction = None;
+                let mut handle_tile = |x: usize, y: usize, direction: Direction| {
                     let index = x + y * width;
+                    let tiles = &tiles[direction.to_u8() as usize];
                     let wave = &mut self.waves[index];
                     let mut entropy = None;
 
                     for tile_index in 0..self.tiles.len() {
-                        if !tiles[tile_index] && wave[tile_index] {
+                        if tiles[tile_index] || !wave[tile_index] {
+                            continue;
+                        }
+
+                        // Collapsed cells keep `sum_one` at zero, so losing their tile is a
+                        // contradiction just like losing the last possibility of an open cell.
+                        if self.sum_one[index] <= 1 {
+                            contradiction = Some(index);
+                            return;
+                        }
+
+                        if entropy.is_none() {
                             entropy = Some(self.entropies[index]);
-                            wave[tile_index] = false;
-                            self.sum_one[index] -= 1;
-
-                            if self.sum_one[index] == 0 {
-                                self.sum_weight[index] = 0f32;
-                                self.sum_weight_log_weight[index] = 0f32;
-                                self.entropies[index] = f32::NAN;
-                            } else {
-                                self.sum_weight[index] -= self.tiles[tile_index].weight;
-                                self.sum_weight_log_weight[index] -= self.tiles[tile_index].weight
-                                    * self.tiles[tile_index].weight.log2();
-                                self.entropies[index] = self.sum_weight[index].log2()
-                                    - self.sum_weight_log_weight[index] / self.sum_weight[index]
-                                    + rand_distr::Normal::new(0f32, 0.1f32)
-                                        .unwrap()
-                                        .sample(&mut rng);
-                            }
                         }
+
+                        wave[tile_index] = false;
+                        self.sum_one[index] -= 1;
+                        self.sum_weight[index] -= self.tiles[tile_index].weight;
+                        self.sum_weight_log_weight[index] -=
+                            self.tiles[tile_index].weight * self.tiles[tile_index].weight.log2();
+                        self.entropies[index] = self.sum_weight[index].log2()
+                            - self.sum_weight_log_weight[index] / self.sum_weight[index]
+                            + rand_distr::Normal::new(0f32, 0.1f32).unwrap().sample(rng);
                     }
 
-                    match entropy {
-                        Some(entropy) => {
-                            let entropy = ordered_float::OrderedFloat::from(entropy);
-
-                            match min_heap.get_mut(&entropy) {
-                                Some(indices) => {
-                                    indices.remove(
-                                        indices
-                                            .iter()
-                                            .position(|wave_index| *wave_index == index)
-                                            .unwrap(),
-                                    );
-
-                                    if min_heap[&entropy].len() == 0 {
-                                        min_heap.remove(&entropy);
-                                    }
-                                }
-                                None => {}
-                            }
+                    if let Some(entropy) = entropy {
+                        let entropy = ordered_float::OrderedFloat::from(entropy);
+
+                        if let Some(indices) = min_heap.get_mut(&entropy) {
+                            indices.remove(
+                                indices
+                                    .iter()
+                                    .position(|wave_index| *wave_index == index)
+                                    .unwrap(),
+                            );
 
-                            if self.sum_one[index] != 0 {
-                                stack.push(index);
-                                match min_heap.get_mut(&ordered_float::OrderedFloat::from(
-                                    self.entropies[index],
-                                )) {
-                                    Some(indices) => {
-                                        indices.push(index);
-                                    }
-                                    None => {
-                                        min_heap.insert(
-                                            ordered_float::OrderedFloat::from(
-                                                self.entropies[index],
-                                            ),
-                                            vec![index],
-                                        );
-                                    }
-                                }
+                            if indices.is_empty() {
+                                min_heap.remove(&entropy);
                             }
                         }
-                        _ => {}
+
+                        if self.sum_one[index] != 0 {
+                            stack.push(index);
+                            min_heap
+                                .entry(ordered_float::OrderedFloat::from(self.entropies[index]))
+                                .or_default()
+                                .push(index);
+                        }
                     }
                 };
 
@@ -276,40 +606,240 @@ impl Generator {
                 let y = index / width;
 
                 if y != 0 {
-                    handle_tile(x, y - 1);
+                    handle_tile(x, y - 1, Direction::Up);
                 }
 
                 if y != height - 1 {
-                    handle_tile(x, y + 1);
+                    handle_tile(x, y + 1, Direction::Down);
                 }
 
                 if x != 0 {
-                    handle_tile(x - 1, y);
+                    handle_tile(x - 1, y, Direction::Left);
                 }
 
                 if x != width - 1 {
-                    handle_tile(x + 1, y);
+                    handle_tile(x + 1, y, Direction::Right);
+                }
+
+                if let Some(index) = contradiction {
+                    return Err(index);
                 }
             }
+        }
+
+        let mut result: Vec<usize> = Vec::with_capacity(self.waves.len());
 
-            visited_set.clear();
+        for (index, wave) in self.waves.iter().enumerate() {
+            match wave.iter().position(|&possibility| possibility) {
+                Some(tile_index) => result.push(tile_index),
+                None => return Err(index),
+            }
         }
 
-        let mut result: Vec<u8> = Vec::with_capacity(self.waves.len());
+        Ok(result)
+    }
+}
 
-        for wave in self.waves.iter() {
-            for index in 0..wave.len() {
-                if wave[index] {
-                    result.push(self.tiles[index].id);
-                    break;
-                }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn rng() -> rand_pcg::Pcg64 {
+        rand_pcg::Pcg64::seed_from_u64(1)
+    }
+
+    fn generator(ids: &[u8]) -> Generator {
+        let mut generator = Generator::new();
+
+        for &id in ids.iter() {
+            generator.add_tile(id, 1f32);
+        }
+
+        generator
+    }
+
+    /// Vertical stripes: the two 2×2 windows are `01` and `10` on both rows, the first one
+    /// occurring twice.
+    fn stripes() -> Overlapping {
+        Overlapping::learn(&[0, 1, 0, 1, 0, 1, 0, 1], 4, 2, 2).unwrap()
+    }
+
+    #[test]
+    fn learning_counts_each_distinct_window() {
+        let overlapping = stripes();
+
+        assert_eq!(overlapping.pattern_count(), 2);
+        assert_eq!(
+            overlapping.patterns,
+            vec![vec![0, 1, 0, 1], vec![1, 0, 1, 0]]
+        );
+        assert_eq!(overlapping.weights, vec![2f32, 1f32]);
+    }
+
+    #[test]
+    fn patterns_agree_where_they_overlap() {
+        let overlapping = stripes();
+
+        assert!(overlapping.agrees(0, Direction::Right, 1));
+        assert!(overlapping.agrees(1, Direction::Right, 0));
+        assert!(overlapping.agrees(0, Direction::Left, 1));
+        assert!(!overlapping.agrees(0, Direction::Right, 0));
+        assert!(overlapping.agrees(0, Direction::Up, 0));
+        assert!(overlapping.agrees(0, Direction::Down, 0));
+        assert!(!overlapping.agrees(0, Direction::Down, 1));
+    }
+
+    #[test]
+    fn blocks_past_the_grid_come_from_the_edge_patterns() {
+        let overlapping = stripes();
+
+        // A 4×3 output on a 3×2 grid of 2×2 patterns.
+        assert_eq!(overlapping.locate(0, 0, 3, 2), (0, 0));
+        assert_eq!(overlapping.locate(1, 1, 3, 2), (4, 0));
+        assert_eq!(overlapping.locate(3, 0, 3, 2), (2, 1));
+        assert_eq!(overlapping.locate(0, 2, 3, 2), (3, 2));
+        assert_eq!(overlapping.locate(3, 2, 3, 2), (5, 3));
+    }
+
+    #[test]
+    fn generated_maps_extend_the_sample() {
+        let blocks = stripes().generate(&mut rng(), 6, 4, &[(0, 0, 1)]).unwrap();
+
+        for (index, &block) in blocks.iter().enumerate() {
+            assert_eq!(block, ((index % 6) as u8 + 1) % 2);
+        }
+    }
+
+    #[test]
+    fn invalid_samples_and_pins_are_rejected() {
+        let sample = [0, 1, 0, 1];
+
+        assert!(matches!(
+            Overlapping::learn(&sample, 2, 2, 0),
+            Err(GenerateError::InvalidSample)
+        ));
+        assert!(matches!(
+            Overlapping::learn(&sample, 2, 2, 3),
+            Err(GenerateError::InvalidSample)
+        ));
+        assert!(matches!(
+            Overlapping::learn(&sample, 4, 2, 2),
+            Err(GenerateError::InvalidSample)
+        ));
+        assert_eq!(
+            stripes().generate(&mut rng(), 1, 4, &[]),
+            Err(GenerateError::InvalidSample)
+        );
+        assert_eq!(
+            stripes().generate(&mut rng(), 4, 4, &[(4, 0, 0)]),
+            Err(GenerateError::InvalidInitialConstraints)
+        );
+        assert_eq!(
+            stripes().generate(&mut rng(), 4, 4, &[(0, 0, 2)]),
+            Err(GenerateError::InvalidInitialConstraints)
+        );
+    }
+
+    #[test]
+    fn allowing_a_neighbor_derives_the_opposite_rule() {
+        let mut rules = Rules::new(3);
+
+        rules.allow(0, Direction::Up, 1);
+
+        assert!(rules.allows(0, Direction::Up, 1));
+        assert!(rules.allows(1, Direction::Down, 0));
+        assert!(!rules.allows(1, Direction::Up, 0));
+        assert!(!rules.allows(0, Direction::Down, 1));
+        assert!(!rules.allows(0, Direction::Left, 1));
+        assert!(!rules.allows(0, Direction::Up, 2));
+
+        rules.allow(2, Direction::Right, 2);
+
+        assert!(rules.allows(2, Direction::Left, 2));
+        assert!(!rules.allows(2, Direction::Up, 2));
+    }
+
+    #[test]
+    fn generated_maps_follow_the_rules() {
+        // Walls may only touch floor, so they end up scattered between floor blocks.
+        let rules = Rules::from_neighbors(&[vec![0, 1], vec![0]]);
+        let blocks = generator(&[5, 7])
+            .generate(&mut rng(), 8, 6, &rules, None)
+            .unwrap();
+
+        assert_eq!(blocks.len(), 48);
+        assert!(blocks.iter().all(|&block| block == 5 || block == 7));
+
+        for (index, &block) in blocks.iter().enumerate() {
+            if block == 7 && index % 8 != 7 {
+                assert_eq!(blocks[index + 1], 5);
+            }
+
+            if block == 7 && index + 8 < blocks.len() {
+                assert_eq!(blocks[index + 8], 5);
             }
         }
+    }
+
+    #[test]
+    fn initial_constraints_are_kept() {
+        let rules = Rules::from_neighbors(&[vec![0, 1], vec![0, 1]]);
+        let mut initial = vec![vec![true, true]; 9];
+
+        initial[4] = vec![false, true];
+
+        let cells = generator(&[0, 1])
+            .collapse(&mut rng(), 3, 3, &rules, Some(initial))
+            .unwrap();
+
+        assert_eq!(cells[4], 1);
+    }
+
+    #[test]
+    fn unsatisfiable_rules_contradict_on_every_attempt() {
+        let mut generator = generator(&[0, 1]);
+
+        generator.set_max_attempts(3);
+
+        match generator.generate(&mut rng(), 4, 4, &Rules::new(2), None) {
+            Err(GenerateError::Contradiction { attempts, x, y }) => {
+                assert_eq!(attempts, 3);
+                assert!(x < 4 && y < 4);
+            }
+            result => panic!("expected a contradiction, got {:?}", result),
+        }
+    }
 
-        if result.len() == self.waves.len() {
-            Some(result)
-        } else {
-            None
+    #[test]
+    fn invalid_setups_are_rejected() {
+        let rules = Rules::from_neighbors(&[vec![0, 1], vec![0, 1]]);
+
+        assert_eq!(
+            generator(&[]).generate(&mut rng(), 2, 2, &Rules::new(0), None),
+            Err(GenerateError::NoTiles)
+        );
+        assert_eq!(
+            generator(&[0, 1]).generate(&mut rng(), 2, 2, &Rules::new(3), None),
+            Err(GenerateError::InvalidRules)
+        );
+
+        for initial in [
+            vec![vec![true, true]; 3],
+            vec![vec![true, true, true]; 4],
+            vec![
+                vec![true, true],
+                vec![false, false],
+                vec![true, true],
+                vec![true, true],
+            ],
+        ]
+        .iter()
+        {
+            assert_eq!(
+                generator(&[0, 1]).generate(&mut rng(), 2, 2, &rules, Some(initial.clone())),
+                Err(GenerateError::InvalidInitialConstraints)
+            );
         }
     }
 }
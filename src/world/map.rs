@@ -1,11 +1,16 @@
+use super::object::Direction;
+
+/// Block id stored in every cell of a map; its properties live in `tile::TileRegistry`.
+pub type Tile = u8;
+
 pub struct Map {
-    data: Vec<u8>,
+    data: Vec<Tile>,
     width: u32,
     height: u32,
 }
 
 impl Map {
-    pub fn from(width: u32, height: u32, data: Vec<u8>) -> Map {
+    pub fn from(width: u32, height: u32, data: Vec<Tile>) -> Map {
         Map {
             data,
             width,
@@ -21,11 +26,203 @@ impl Map {
         self.height
     }
 
-    pub fn data(&self) -> &Vec<u8> {
+    pub fn data(&self) -> &Vec<Tile> {
         &self.data
     }
 
-    pub fn get_block(&self, x: u32, y: u32) -> u8 {
-        self.data[(x + y * self.width) as usize]
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        0 <= x && (x as i64) < self.width as i64 && 0 <= y && (y as i64) < self.height as i64
+    }
+
+    /// Row-major index of the block at `(x, y)`, or `None` if it lies outside the map.
+    pub fn index(&self, x: i32, y: i32) -> Option<usize> {
+        if self.contains(x, y) {
+            Some(x as usize + y as usize * self.width as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Coordinates of the block at row-major `index`.
+    pub fn position(&self, index: usize) -> (i32, i32) {
+        (
+            (index % self.width as usize) as i32,
+            (index / self.width as usize) as i32,
+        )
+    }
+
+    pub fn get(&self, x: i32, y: i32) -> Option<Tile> {
+        self.index(x, y)
+            .and_then(|index| self.data.get(index))
+            .copied()
+    }
+
+    /// Replaces the block at `(x, y)`; returns `false` if it lies outside the map.
+    pub fn set_block(&mut self, x: i32, y: i32, tile: Tile) -> bool {
+        match self.index(x, y).and_then(|index| self.data.get_mut(index)) {
+            Some(block) => {
+                *block = tile;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The blocks next to `(x, y)` that lie inside the map, with the direction leading to each
+    /// and its coordinates.
+    pub fn neighbors(
+        &self,
+        x: i32,
+        y: i32,
+    ) -> impl Iterator<Item = (Direction, (i32, i32), Tile)> + '_ {
+        Direction::ALL.iter().filter_map(move |direction| {
+            let (dx, dy) = direction.offset();
+            let (x, y) = (x.checked_add(dx)?, y.checked_add(dy)?);
+
+            self.get(x, y).map(|tile| (*direction, (x, y), tile))
+        })
+    }
+
+    /// Row-major indices of the blocks next to block `index`, each paired with the direction
+    /// leading to it.
+    pub fn neighbor_indices(&self, index: usize) -> impl Iterator<Item = (Direction, usize)> + '_ {
+        let (x, y) = self.position(index);
+
+        self.neighbors(x, y)
+            .filter_map(move |(direction, (x, y), _)| Some((direction, self.index(x, y)?)))
+    }
+
+    /// Blocks of row `y`, or `None` if it lies outside the map.
+    pub fn row(&self, y: i32) -> Option<&[Tile]> {
+        let start = self.index(0, y)?;
+
+        self.data.get(start..start + self.width as usize)
+    }
+
+    /// Rows of the `width`×`height` rectangle whose top-left corner is `(x, y)`, clipped to the
+    /// map. Rows and columns outside the map are left out rather than padded.
+    pub fn region(
+        &self,
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+    ) -> impl Iterator<Item = &[Tile]> + '_ {
+        let left = std::cmp::max(x as i64, 0);
+        let right = std::cmp::min(x as i64 + width as i64, self.width as i64);
+        let top = std::cmp::max(y as i64, 0);
+        let bottom = std::cmp::min(y as i64 + height as i64, self.height as i64);
+
+        (top..bottom).filter_map(move |y| {
+            if left >= right {
+                return None;
+            }
+
+            self.row(y as i32)
+                .map(|row| &row[left as usize..right as usize])
+        })
+    }
+}
+
+#[cfg(test)]
+impl Map {
+    /// Builds a map from rows of `#` for walls (block 1) and `.` for floor (block 0).
+    pub fn parse(rows: &[&str]) -> Map {
+        let data = rows
+            .iter()
+            .flat_map(|row| row.chars())
+            .map(|block| if block == '#' { 1 } else { 0 })
+            .collect();
+
+        Map::from(rows[0].len() as u32, rows.len() as u32, data)
+    }
+}
+
+/// Whether a block of a map built by `Map::parse` is floor.
+#[cfg(test)]
+pub fn is_floor(block: Tile) -> bool {
+    block == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 4×3 map whose blocks are numbered in row-major order.
+    fn map() -> Map {
+        Map::from(4, 3, (0..12).collect())
+    }
+
+    #[test]
+    fn positions_outside_the_map_are_rejected() {
+        let mut map = map();
+
+        assert_eq!(map.get(3, 2), Some(11));
+        assert_eq!(map.index(1, 2), Some(9));
+        assert_eq!(map.position(9), (1, 2));
+
+        for &(x, y) in [
+            (-1, 0),
+            (0, -1),
+            (4, 0),
+            (0, 3),
+            (i32::MIN, 0),
+            (i32::MAX, 0),
+            (0, i32::MAX),
+            (i32::MAX, i32::MAX),
+        ]
+        .iter()
+        {
+            assert!(!map.contains(x, y));
+            assert_eq!(map.index(x, y), None);
+            assert_eq!(map.get(x, y), None);
+            assert!(!map.set_block(x, y, 0));
+        }
+
+        assert_eq!(map.data(), &(0..12).collect::<Vec<Tile>>());
+    }
+
+    #[test]
+    fn neighbors_stay_inside_the_map() {
+        let map = map();
+        let corner: Vec<_> = map.neighbors(0, 0).collect();
+
+        assert_eq!(
+            corner,
+            vec![(Direction::Down, (0, 1), 4), (Direction::Right, (1, 0), 1)]
+        );
+        assert_eq!(map.neighbors(1, 1).count(), 4);
+        assert_eq!(map.neighbors(i32::MAX, i32::MAX).count(), 0);
+        assert_eq!(map.neighbors(i32::MIN, 0).count(), 0);
+        assert_eq!(
+            map.neighbor_indices(11)
+                .map(|(_, index)| index)
+                .collect::<Vec<_>>(),
+            vec![7, 10]
+        );
+    }
+
+    #[test]
+    fn rows_outside_the_map_are_missing() {
+        let map = map();
+
+        assert_eq!(map.row(1), Some(&[4, 5, 6, 7][..]));
+        assert_eq!(map.row(-1), None);
+        assert_eq!(map.row(3), None);
+        assert_eq!(map.row(i32::MAX), None);
+    }
+
+    #[test]
+    fn regions_are_clipped_to_the_map() {
+        let map = map();
+        let region = |x, y, width, height| map.region(x, y, width, height).collect::<Vec<_>>();
+
+        assert_eq!(region(1, 1, 2, 2), vec![&[5, 6][..], &[9, 10][..]]);
+        assert_eq!(region(-1, -1, 3, 2), vec![&[0, 1][..]]);
+        assert_eq!(region(2, 1, 10, 10), vec![&[6, 7][..], &[10, 11][..]]);
+        assert_eq!(region(0, 2, u32::MAX, u32::MAX), vec![&[8, 9, 10, 11][..]]);
+        assert_eq!(region(i32::MAX, 0, u32::MAX, 3), Vec::<&[Tile]>::new());
+        assert_eq!(region(i32::MIN, i32::MIN, 2, 2), Vec::<&[Tile]>::new());
+        assert_eq!(region(1, 0, 0, 3), Vec::<&[Tile]>::new());
     }
 }
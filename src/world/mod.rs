@@ -1,5 +1,10 @@
+pub mod connectivity;
 pub mod generator;
 pub mod map;
 pub mod object;
+pub mod pathfinding;
 pub mod player;
+pub mod room;
+pub mod tile;
+#[allow(clippy::module_inception)]
 pub mod world;
@@ -0,0 +1,230 @@
+use super::map::Map;
+use super::object::Direction;
+
+/// Estimates the number of moves between two blocks to guide `find_path`. The path found is only
+/// guaranteed to be a shortest one if the estimate never exceeds the real distance.
+pub trait Heuristic {
+    fn estimate(&self, from: (i32, i32), to: (i32, i32)) -> usize;
+}
+
+/// Horizontal plus vertical distance, the exact number of moves on a map without walls.
+pub struct Manhattan;
+
+impl Heuristic for Manhattan {
+    fn estimate(&self, from: (i32, i32), to: (i32, i32)) -> usize {
+        ((from.0 as i64 - to.0 as i64).abs() + (from.1 as i64 - to.1 as i64).abs()) as usize
+    }
+}
+
+/// No estimate at all, which makes `find_path` a plain breadth-first search.
+pub struct Zero;
+
+impl Heuristic for Zero {
+    fn estimate(&self, _: (i32, i32), _: (i32, i32)) -> usize {
+        0
+    }
+}
+
+impl<F> Heuristic for F
+where
+    F: Fn((i32, i32), (i32, i32)) -> usize,
+{
+    fn estimate(&self, from: (i32, i32), to: (i32, i32)) -> usize {
+        self(from, to)
+    }
+}
+
+/// Finds a shortest walkable path from `from` to `to` with A*, returning every block along it
+/// with both ends included, or `None` if `to` cannot be reached.
+pub fn find_path<F, H>(
+    map: &Map,
+    from: (i32, i32),
+    to: (i32, i32),
+    walkable: F,
+    heuristic: &H,
+) -> Option<Vec<(i32, i32)>>
+where
+    F: Fn(u8) -> bool,
+    H: Heuristic,
+{
+    let start = map.index(from.0, from.1)?;
+    let goal = map.index(to.0, to.1)?;
+
+    if !walkable(map.data()[start]) || !walkable(map.data()[goal]) {
+        return None;
+    }
+
+    let mut costs: Vec<Option<usize>> = vec![None; map.data().len()];
+    let mut previous: Vec<Option<usize>> = vec![None; map.data().len()];
+    let mut open = std::collections::BinaryHeap::new();
+
+    costs[start] = Some(0);
+    open.push(std::cmp::Reverse((heuristic.estimate(from, to), 0, start)));
+
+    while let Some(std::cmp::Reverse((_, cost, index))) = open.pop() {
+        if index == goal {
+            let mut path = vec![map.position(index)];
+            let mut index = index;
+
+            while let Some(next) = previous[index] {
+                path.push(map.position(next));
+                index = next;
+            }
+
+            path.reverse();
+
+            return Some(path);
+        }
+
+        // A block may be queued several times; only its cheapest entry is expanded.
+        if costs[index].is_some_and(|best| best < cost) {
+            continue;
+        }
+
+        for (_, neighbor) in map.neighbor_indices(index) {
+            if !walkable(map.data()[neighbor])
+                || costs[neighbor].is_some_and(|best| best <= cost + 1)
+            {
+                continue;
+            }
+
+            costs[neighbor] = Some(cost + 1);
+            previous[neighbor] = Some(index);
+            open.push(std::cmp::Reverse((
+                cost + 1 + heuristic.estimate(map.position(neighbor), to),
+                cost + 1,
+                neighbor,
+            )));
+        }
+    }
+
+    None
+}
+
+/// Number of moves needed to reach `target` from every block, in row-major order; `None` for
+/// blocks from which it cannot be reached.
+pub fn distance_field<F>(map: &Map, target: (i32, i32), walkable: F) -> Vec<Option<usize>>
+where
+    F: Fn(u8) -> bool,
+{
+    let mut distances = vec![None; map.data().len()];
+    let mut queue = std::collections::VecDeque::new();
+    let target = match map.index(target.0, target.1) {
+        Some(target) if walkable(map.data()[target]) => target,
+        _ => return distances,
+    };
+
+    distances[target] = Some(0);
+    queue.push_back(target);
+
+    while let Some(index) = queue.pop_front() {
+        let distance = distances[index].unwrap() + 1;
+
+        for (_, neighbor) in map.neighbor_indices(index) {
+            if distances[neighbor].is_none() && walkable(map.data()[neighbor]) {
+                distances[neighbor] = Some(distance);
+                queue.push_back(neighbor);
+            }
+        }
+    }
+
+    distances
+}
+
+/// The first move of a shortest path from `from` to the target of `field`, or `None` if the
+/// target is unreachable or already reached.
+pub fn next_step(map: &Map, field: &[Option<usize>], from: (i32, i32)) -> Option<Direction> {
+    let index = map.index(from.0, from.1)?;
+    let distance = (*field.get(index)?)?;
+
+    if distance == 0 {
+        return None;
+    }
+
+    map.neighbor_indices(index)
+        .find(|&(_, neighbor)| field.get(neighbor) == Some(&Some(distance - 1)))
+        .map(|(direction, _)| direction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::map::is_floor;
+    use super::*;
+
+    fn maze() -> Map {
+        Map::parse(&[
+            ".....#....",
+            ".###.#.##.",
+            ".#...#..#.",
+            ".#.####.#.",
+            ".#......#.",
+            ".######.#.",
+            "........#.",
+        ])
+    }
+
+    #[test]
+    fn unreachable_goals_have_no_path() {
+        let map = Map::parse(&["..#..", "..#..", "..#.."]);
+
+        assert_eq!(find_path(&map, (0, 0), (4, 2), is_floor, &Manhattan), None);
+        assert_eq!(find_path(&map, (0, 0), (2, 1), is_floor, &Manhattan), None);
+        assert_eq!(find_path(&map, (0, 0), (5, 0), is_floor, &Manhattan), None);
+        assert_eq!(find_path(&map, (-1, 0), (1, 0), is_floor, &Zero), None);
+    }
+
+    #[test]
+    fn heuristics_find_equally_short_paths() {
+        let map = maze();
+        let manhattan = find_path(&map, (0, 0), (9, 6), is_floor, &Manhattan).unwrap();
+        let zero = find_path(&map, (0, 0), (9, 6), is_floor, &Zero).unwrap();
+
+        assert_eq!(manhattan.len(), zero.len());
+        assert_eq!(manhattan.len() - 1, 29);
+        assert_eq!(manhattan.first(), Some(&(0, 0)));
+        assert_eq!(manhattan.last(), Some(&(9, 6)));
+
+        for path in [manhattan, zero].iter() {
+            for step in path.windows(2) {
+                let (from, to) = (step[0], step[1]);
+
+                assert_eq!(Manhattan.estimate(from, to), 1);
+                assert!(is_floor(map.get(to.0, to.1).unwrap()));
+            }
+        }
+    }
+
+    #[test]
+    fn paths_match_the_distance_field() {
+        let map = maze();
+        let field = distance_field(&map, (9, 6), is_floor);
+
+        assert_eq!(field[map.index(9, 6).unwrap()], Some(0));
+        assert_eq!(field[map.index(0, 0).unwrap()], Some(29));
+        assert_eq!(field[map.index(5, 0).unwrap()], None);
+        assert!(distance_field(&map, (5, 0), is_floor)
+            .iter()
+            .all(Option::is_none));
+    }
+
+    #[test]
+    fn next_step_leads_to_the_target() {
+        let map = maze();
+        let field = distance_field(&map, (9, 6), is_floor);
+        let mut position = (0, 0);
+        let mut steps = 0;
+
+        while let Some(direction) = next_step(&map, &field, position) {
+            let (dx, dy) = direction.offset();
+
+            position = (position.0 + dx, position.1 + dy);
+            steps += 1;
+        }
+
+        assert_eq!(position, (9, 6));
+        assert_eq!(steps, 29);
+        assert_eq!(next_step(&map, &field, (9, 6)), None);
+        assert_eq!(next_step(&map, &field, (5, 0)), None);
+        assert_eq!(next_step(&map, &field, (10, 0)), None);
+    }
+}
@@ -2,26 +2,47 @@ use super::object;
 
 use byteorder::WriteBytesExt;
 
+/// Longest nickname accepted by `is_valid_name`, in characters.
+pub const MAX_NAME_LENGTH: usize = 16;
+
+/// Whether `name` can be shown to other players: 1 to `MAX_NAME_LENGTH` letters, digits, spaces,
+/// `-`, `_` or `.`, not starting or ending with a space.
+pub fn is_valid_name(name: &str) -> bool {
+	!name.is_empty()
+		&& name.chars().count() <= MAX_NAME_LENGTH
+		&& name.trim() == name
+		&& name
+			.chars()
+			.all(|c| c.is_alphanumeric() || c == ' ' || c == '-' || c == '_' || c == '.')
+}
+
 pub struct Player {
 	id: u64,
+	name: String,
 	color: (u8, u8, u8),
 	object: object::Object,
+	inputs: std::collections::VecDeque<object::Direction>,
+	cooldown: u32,
 }
 
 impl Player {
-	pub fn new(id: u64, x: i32, y: i32) -> Player {
+	/// Creates a player at `(x, y)`; without a preferred color, one is derived from the id.
+	pub fn new(id: u64, name: String, color: Option<(u8, u8, u8)>, x: i32, y: i32) -> Player {
 		let mut bytes = vec![];
 
 		bytes.write_u64::<byteorder::LittleEndian>(id).unwrap();
 
 		Player {
 			id,
-			color: (
+			name,
+			color: color.unwrap_or((
 				std::cmp::min(bytes[0] as u32 + 64, 255) as u8,
 				std::cmp::min(bytes[1] as u32 + 64, 255) as u8,
 				std::cmp::min(bytes[2] as u32 + 64, 255) as u8,
-			),
+			)),
 			object: object::Object::new(x, y),
+			inputs: std::collections::VecDeque::new(),
+			cooldown: 0,
 		}
 	}
 
@@ -29,6 +50,10 @@ impl Player {
 		self.id
 	}
 
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
 	pub fn color(&self) -> (u8, u8, u8) {
 		self.color
 	}
@@ -40,4 +65,84 @@ impl Player {
 	pub fn object_mut(&mut self) -> &mut object::Object {
 		&mut self.object
 	}
+
+	pub fn inputs_mut(&mut self) -> &mut std::collections::VecDeque<object::Direction> {
+		&mut self.inputs
+	}
+
+	pub fn cooldown(&self) -> u32 {
+		self.cooldown
+	}
+
+	pub fn set_cooldown(&mut self, cooldown: u32) {
+		self.cooldown = cooldown;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn names_of_letters_digits_and_separators_are_valid() {
+		for name in [
+			"a",
+			"Ariane",
+			"x_1-2.3",
+			"two words",
+			"Zoë",
+			"Ελένη",
+			"名前",
+		]
+		.iter()
+		{
+			assert!(is_valid_name(name), "{}", name);
+		}
+
+		assert!(is_valid_name(&"a".repeat(MAX_NAME_LENGTH)));
+		assert!(is_valid_name(&"é".repeat(MAX_NAME_LENGTH)));
+	}
+
+	#[test]
+	fn empty_and_overlong_names_are_invalid() {
+		assert!(!is_valid_name(""));
+		assert!(!is_valid_name(&"a".repeat(MAX_NAME_LENGTH + 1)));
+		assert!(!is_valid_name(&"é".repeat(MAX_NAME_LENGTH + 1)));
+	}
+
+	#[test]
+	fn padded_names_are_invalid() {
+		for name in [
+			" ",
+			" Ariane",
+			"Ariane ",
+			"\tAriane",
+			"Ariane\n",
+			"\u{a0}Ariane",
+		]
+		.iter()
+		{
+			assert!(!is_valid_name(name), "{:?}", name);
+		}
+	}
+
+	#[test]
+	fn control_and_punctuation_characters_are_invalid() {
+		for name in [
+			"a\u{0}b",
+			"a\u{7}b",
+			"a\tb",
+			"a\u{202e}b",
+			"a!",
+			"<b>",
+			"a/b",
+			"a:b",
+			"@all",
+			"a\u{200b}b",
+		]
+		.iter()
+		{
+			assert!(!is_valid_name(name), "{:?}", name);
+		}
+	}
 }
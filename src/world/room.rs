@@ -0,0 +1,541 @@
+extern crate log;
+extern crate serde;
+extern crate toml;
+
+/// Longest room name accepted by `RoomManager::create`, in characters.
+pub const MAX_ROOM_NAME_LENGTH: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RoomError {
+    /// No room has the requested id.
+    NotFound,
+    /// The room already holds the configured maximum number of players.
+    Full,
+    /// The server already hosts the configured maximum number of rooms.
+    TooManyRooms,
+    /// The name is empty or longer than `MAX_ROOM_NAME_LENGTH`.
+    InvalidName,
+    /// The room's world could not be generated.
+    Generation(super::generator::GenerateError),
+}
+
+impl std::fmt::Display for RoomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RoomError::NotFound => write!(f, "no such room"),
+            RoomError::Full => write!(f, "room is full"),
+            RoomError::TooManyRooms => write!(f, "too many rooms"),
+            RoomError::InvalidName => write!(f, "invalid room name"),
+            RoomError::Generation(err) => write!(f, "failed to generate the world: {}", err),
+        }
+    }
+}
+
+impl From<super::generator::GenerateError> for RoomError {
+    fn from(err: super::generator::GenerateError) -> RoomError {
+        RoomError::Generation(err)
+    }
+}
+
+/// What `RoomManager::save` keeps of a persistent room: enough to generate its current map again.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+struct SavedRoom {
+    name: String,
+    /// The bits of the `u64` seed, as TOML integers are signed.
+    seed: i64,
+}
+
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+struct SavedState {
+    #[serde(default)]
+    rooms: Vec<SavedRoom>,
+}
+
+/// A world together with the players sharing it.
+pub struct Room {
+    id: u32,
+    name: String,
+    persistent: bool,
+    world: super::world::World,
+}
+
+impl Room {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Persistent rooms are kept when their last player leaves.
+    pub fn is_persistent(&self) -> bool {
+        self.persistent
+    }
+
+    pub fn world(&self) -> &super::world::World {
+        &self.world
+    }
+
+    pub fn world_mut(&mut self) -> &mut super::world::World {
+        &mut self.world
+    }
+}
+
+/// A room whose world is being generated on another thread.
+struct PendingRoom {
+    id: u32,
+    name: String,
+    persistent: bool,
+    /// Whether matchmaking asked for it, so that later players wait for it as well.
+    matchmaking: bool,
+    world: std::thread::JoinHandle<Result<super::world::World, super::generator::GenerateError>>,
+}
+
+/// Owns every room of the server, each generated from the same world configuration.
+pub struct RoomManager {
+    config: super::super::config::WorldConfig,
+    max_rooms: usize,
+    max_players: usize,
+    next_id: u32,
+    rooms: Vec<Room>,
+    pending: Vec<PendingRoom>,
+}
+
+impl RoomManager {
+    pub fn new(
+        config: super::super::config::WorldConfig,
+        max_rooms: usize,
+        max_players: usize,
+    ) -> RoomManager {
+        RoomManager {
+            config,
+            max_rooms,
+            max_players,
+            next_id: 1,
+            rooms: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn rooms(&self) -> &Vec<Room> {
+        &self.rooms
+    }
+
+    pub fn rooms_mut(&mut self) -> &mut Vec<Room> {
+        &mut self.rooms
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Room> {
+        self.rooms.iter().find(|room| room.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Room> {
+        self.rooms.iter_mut().find(|room| room.id == id)
+    }
+
+    pub fn max_players(&self) -> usize {
+        self.max_players
+    }
+
+    /// Number of players across every room.
+    pub fn player_count(&self) -> usize {
+        self.rooms
+            .iter()
+            .map(|room| room.world.players().len())
+            .sum()
+    }
+
+    /// Generates a new room on the calling thread and returns its id.
+    pub fn create(&mut self, name: &str, persistent: bool) -> Result<u32, RoomError> {
+        self.add(name, persistent, None)
+    }
+
+    /// Writes the name and current map seed of every persistent room to `path`. Players and the
+    /// progress of the round are not saved.
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let state = SavedState {
+            rooms: self
+                .rooms
+                .iter()
+                .filter(|room| room.persistent)
+                .map(|room| SavedRoom {
+                    name: room.name.clone(),
+                    seed: room.world.seed() as i64,
+                })
+                .collect(),
+        };
+        let content = toml::to_string(&state)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+
+        std::fs::write(path, content)
+    }
+
+    /// Recreates the persistent rooms saved to `path` with the maps they had, provided the world
+    /// configuration did not change, and returns how many were restored. Rooms that cannot be
+    /// created again are skipped.
+    pub fn load(&mut self, path: &str) -> std::io::Result<usize> {
+        let content = std::fs::read_to_string(path)?;
+        let state: SavedState = toml::from_str(&content)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        let mut restored = 0;
+
+        for room in state.rooms {
+            match self.add(&room.name, true, Some(room.seed as u64)) {
+                Ok(..) => restored += 1,
+                Err(err) => log::warn!("failed to restore room \"{}\": {}", room.name, err),
+            }
+        }
+
+        Ok(restored)
+    }
+
+    fn add(&mut self, name: &str, persistent: bool, seed: Option<u64>) -> Result<u32, RoomError> {
+        let (id, name) = self.reserve(name)?;
+        let world = match seed {
+            Some(seed) => super::world::World::new(&super::super::config::WorldConfig {
+                seed: Some(seed),
+                ..self.config.clone()
+            })?,
+            None => super::world::World::new(&self.config)?,
+        };
+
+        log::info!("created room {} \"{}\"", id, name);
+
+        self.rooms.push(Room {
+            id,
+            name,
+            persistent,
+            world,
+        });
+
+        Ok(id)
+    }
+
+    /// Starts generating a new room on another thread, so that the other rooms keep ticking, and
+    /// returns the id it will have once `poll` reports it. The room is generated on the calling
+    /// thread instead if no thread can be started.
+    pub fn generate(&mut self, name: &str, persistent: bool) -> Result<u32, RoomError> {
+        self.spawn(name, persistent, false)
+    }
+
+    fn spawn(&mut self, name: &str, persistent: bool, matchmaking: bool) -> Result<u32, RoomError> {
+        let (id, name) = self.reserve(name)?;
+        let config = self.config.clone();
+        let generator = std::thread::Builder::new()
+            .name("generator".to_owned())
+            .spawn(move || super::world::World::new(&config));
+
+        match generator {
+            Ok(world) => {
+                self.pending.push(PendingRoom {
+                    id,
+                    name,
+                    persistent,
+                    matchmaking,
+                    world,
+                });
+
+                Ok(id)
+            }
+            Err(err) => {
+                log::error!("failed to start generating room {}: {}", id, err);
+
+                let world = super::world::World::new(&self.config)?;
+
+                self.rooms.push(Room {
+                    id,
+                    name,
+                    persistent,
+                    world,
+                });
+
+                Ok(id)
+            }
+        }
+    }
+
+    /// Checks the name of a new room and, if there is space for it, allocates its id.
+    fn reserve(&mut self, name: &str) -> Result<(u32, String), RoomError> {
+        let name = name.trim();
+
+        if name.is_empty() || name.chars().count() > MAX_ROOM_NAME_LENGTH {
+            return Err(RoomError::InvalidName);
+        }
+
+        if self.rooms.len() + self.pending.len() >= self.max_rooms {
+            return Err(RoomError::TooManyRooms);
+        }
+
+        let id = self.next_id;
+
+        self.next_id += 1;
+
+        Ok((id, name.to_owned()))
+    }
+
+    /// Whether the room is still being generated.
+    pub fn is_generating(&self, id: u32) -> bool {
+        self.pending.iter().any(|pending| pending.id == id)
+    }
+
+    /// Adds the rooms whose generation finished since the last call and returns their ids, each
+    /// with the error that kept it from being created, if any.
+    pub fn poll(&mut self) -> Vec<(u32, Result<(), RoomError>)> {
+        let (finished, pending): (Vec<PendingRoom>, Vec<PendingRoom>) =
+            std::mem::take(&mut self.pending)
+                .into_iter()
+                .partition(|pending| pending.world.is_finished());
+        let mut results = Vec::new();
+
+        self.pending = pending;
+
+        for room in finished {
+            let result = match room.world.join() {
+                Ok(Ok(world)) => {
+                    log::info!("created room {} \"{}\"", room.id, room.name);
+
+                    self.rooms.push(Room {
+                        id: room.id,
+                        name: room.name,
+                        persistent: room.persistent,
+                        world,
+                    });
+
+                    Ok(())
+                }
+                Ok(Err(err)) => {
+                    log::error!("failed to generate room {}: {}", room.id, err);
+
+                    Err(RoomError::Generation(err))
+                }
+                Err(..) => {
+                    log::error!("generating room {} panicked", room.id);
+
+                    Err(RoomError::NotFound)
+                }
+            };
+
+            results.push((room.id, result));
+        }
+
+        results
+    }
+
+    /// Returns the room with the most players that still has space, if any.
+    pub fn find(&self) -> Option<u32> {
+        self.rooms
+            .iter()
+            .filter(|room| room.world.players().len() < self.max_players)
+            .max_by_key(|room| room.world.players().len())
+            .map(|room| room.id)
+    }
+
+    /// Returns a room matchmaking is still generating, if any.
+    pub fn find_generating(&self) -> Option<u32> {
+        self.pending
+            .iter()
+            .find(|pending| pending.matchmaking)
+            .map(|pending| pending.id)
+    }
+
+    /// Returns the room a player should join: the one `find` returns, else the one
+    /// `find_generating` returns, else a new one `generate` starts.
+    pub fn find_or_generate(&mut self) -> Result<u32, RoomError> {
+        match self.find().or_else(|| self.find_generating()) {
+            Some(room) => Ok(room),
+            None => {
+                let name = format!("room {}", self.next_id);
+
+                self.spawn(&name, false, true)
+            }
+        }
+    }
+
+    /// Checks that a player may join the room.
+    pub fn admit(&self, id: u32) -> Result<(), RoomError> {
+        match self.get(id) {
+            Some(room) if room.world.players().len() >= self.max_players => Err(RoomError::Full),
+            Some(..) => Ok(()),
+            None => Err(RoomError::NotFound),
+        }
+    }
+
+    /// Drops the room if nobody is left in it and it is not persistent.
+    pub fn remove_if_empty(&mut self, id: u32) {
+        if let Some(index) = self
+            .rooms
+            .iter()
+            .position(|room| room.id == id && !room.persistent && room.world.players().is_empty())
+        {
+            self.rooms.remove(index);
+
+            log::info!("removed empty room {}", id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager() -> RoomManager {
+        RoomManager::new(super::super::super::config::WorldConfig::default(), 4, 8)
+    }
+
+    fn fill(rooms: &mut RoomManager, id: u32, count: u64) {
+        let world = rooms.get_mut(id).unwrap().world_mut();
+
+        for player in 0..count {
+            world.add_player(player, format!("player {}", player), None);
+        }
+    }
+
+    /// Waits for the rooms being generated to finish, as the handler polls every tick.
+    fn poll(rooms: &mut RoomManager) -> Vec<(u32, Result<(), RoomError>)> {
+        loop {
+            let results = rooms.poll();
+
+            if !results.is_empty() {
+                return results;
+            }
+
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn saved_rooms_are_restored_with_their_maps() {
+        let path = std::env::temp_dir().join(format!("mazemaze-rooms-{}.toml", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut rooms = manager();
+
+        rooms.create("main", true).unwrap();
+        rooms.create("lobby", false).unwrap();
+        // Seeds use all 64 bits.
+        rooms.add("quiet", true, Some(u64::MAX)).unwrap();
+        rooms.save(path).unwrap();
+
+        let mut restored = manager();
+        let count = restored.load(path);
+
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(count.unwrap(), 2);
+
+        let saved: Vec<&Room> = rooms
+            .rooms()
+            .iter()
+            .filter(|room| room.is_persistent())
+            .collect();
+
+        assert_eq!(restored.rooms().len(), saved.len());
+
+        for (room, saved) in restored.rooms().iter().zip(saved) {
+            assert_eq!(room.name(), saved.name());
+            assert!(room.is_persistent());
+            assert_eq!(room.world().seed(), saved.world().seed());
+            assert_eq!(room.world().map().data(), saved.world().map().data());
+        }
+    }
+
+    #[test]
+    fn loading_a_missing_file_fails() {
+        let mut rooms = manager();
+        let path =
+            std::env::temp_dir().join(format!("mazemaze-missing-{}.toml", std::process::id()));
+
+        assert_eq!(
+            rooms.load(path.to_str().unwrap()).unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert!(rooms.rooms().is_empty());
+    }
+
+    #[test]
+    fn the_fullest_room_with_space_is_found() {
+        let mut rooms = manager();
+
+        assert_eq!(rooms.find(), None);
+
+        let quiet = rooms.create("quiet", false).unwrap();
+        let busy = rooms.create("busy", false).unwrap();
+        let full = rooms.create("full", false).unwrap();
+
+        fill(&mut rooms, quiet, 1);
+        fill(&mut rooms, busy, 7);
+        fill(&mut rooms, full, 8);
+
+        assert_eq!(rooms.find(), Some(busy));
+        assert_eq!(rooms.admit(full), Err(RoomError::Full));
+        assert_eq!(rooms.admit(busy), Ok(()));
+        assert_eq!(rooms.admit(42), Err(RoomError::NotFound));
+    }
+
+    #[test]
+    fn matchmaking_generates_a_room_once_every_room_is_full() {
+        let mut rooms = manager();
+        let main = rooms.create("main", true).unwrap();
+
+        fill(&mut rooms, main, 8);
+
+        let room = rooms.find_or_generate().unwrap();
+
+        assert_ne!(room, main);
+        assert!(rooms.is_generating(room));
+        assert!(rooms.get(room).is_none());
+        // Players arriving meanwhile wait for the same room.
+        assert_eq!(rooms.find_or_generate(), Ok(room));
+        assert_eq!(poll(&mut rooms), vec![(room, Ok(()))]);
+        assert!(!rooms.is_generating(room));
+        assert_eq!(rooms.get(room).unwrap().name(), "room 2");
+        assert!(!rooms.get(room).unwrap().is_persistent());
+        assert_eq!(rooms.find_or_generate(), Ok(room));
+    }
+
+    #[test]
+    fn rooms_beyond_the_maximum_are_refused() {
+        let mut rooms = manager();
+
+        assert_eq!(rooms.create("  ", false), Err(RoomError::InvalidName));
+        assert_eq!(
+            rooms.generate(&"x".repeat(MAX_ROOM_NAME_LENGTH + 1), false),
+            Err(RoomError::InvalidName)
+        );
+
+        for name in &["a", "b", "c"] {
+            rooms.create(name, false).unwrap();
+        }
+
+        // Rooms still being generated count towards the maximum.
+        let last = rooms.generate("d", false).unwrap();
+
+        assert_eq!(rooms.create("e", false), Err(RoomError::TooManyRooms));
+        assert_eq!(rooms.generate("e", false), Err(RoomError::TooManyRooms));
+        assert_eq!(poll(&mut rooms), vec![(last, Ok(()))]);
+
+        for id in 1..=4 {
+            fill(&mut rooms, id, 8);
+        }
+
+        assert_eq!(rooms.find_or_generate(), Err(RoomError::TooManyRooms));
+    }
+
+    #[test]
+    fn only_empty_rooms_that_are_not_persistent_are_removed() {
+        let mut rooms = manager();
+        let main = rooms.create("main", true).unwrap();
+        let lobby = rooms.create("lobby", false).unwrap();
+        let busy = rooms.create("busy", false).unwrap();
+
+        fill(&mut rooms, busy, 1);
+
+        for id in &[main, lobby, busy] {
+            rooms.remove_if_empty(*id);
+        }
+
+        let names: Vec<&str> = rooms.rooms().iter().map(Room::name).collect();
+
+        assert_eq!(names, vec!["main", "busy"]);
+    }
+}
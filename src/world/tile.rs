@@ -0,0 +1,185 @@
+/// A kind of block, identified on the map by its id.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TileType {
+    pub id: u8,
+    pub name: String,
+    /// Players may stand on it.
+    pub walkable: bool,
+    /// Blocks line of sight.
+    pub opaque: bool,
+    /// Hurts players standing on it.
+    pub damaging: bool,
+    /// Players entering it keep moving in the same direction until something stops them.
+    pub slippery: bool,
+    /// Reaching it finishes the maze; placed on the configured exit.
+    pub exit: bool,
+    /// Players appear on it; placed on the configured spawn.
+    pub spawn: bool,
+}
+
+impl TileType {
+    pub const WALKABLE: u8 = 1 << 0;
+    pub const OPAQUE: u8 = 1 << 1;
+    pub const DAMAGING: u8 = 1 << 2;
+    pub const SLIPPERY: u8 = 1 << 3;
+    pub const EXIT: u8 = 1 << 4;
+    pub const SPAWN: u8 = 1 << 5;
+
+    pub fn from(config: &super::super::config::TileConfig) -> TileType {
+        TileType {
+            id: config.id,
+            name: config.name.clone(),
+            walkable: config.walkable,
+            opaque: config.opaque,
+            damaging: config.damaging,
+            slippery: config.slippery,
+            exit: config.exit,
+            spawn: config.spawn,
+        }
+    }
+
+    /// The properties as a bit set of the constants above, as sent to clients.
+    pub fn flags(&self) -> u8 {
+        [
+            (self.walkable, TileType::WALKABLE),
+            (self.opaque, TileType::OPAQUE),
+            (self.damaging, TileType::DAMAGING),
+            (self.slippery, TileType::SLIPPERY),
+            (self.exit, TileType::EXIT),
+            (self.spawn, TileType::SPAWN),
+        ]
+        .iter()
+        .filter(|&&(set, _)| set)
+        .fold(0, |flags, &(_, flag)| flags | flag)
+    }
+}
+
+/// Every tile type of a world, looked up by block id. Ids without a type behave as solid,
+/// nameless blocks.
+pub struct TileRegistry {
+    types: Vec<TileType>,
+}
+
+impl TileRegistry {
+    pub fn from(tiles: &[super::super::config::TileConfig]) -> TileRegistry {
+        TileRegistry {
+            types: tiles.iter().map(TileType::from).collect(),
+        }
+    }
+
+    pub fn types(&self) -> &Vec<TileType> {
+        &self.types
+    }
+
+    pub fn get(&self, id: u8) -> Option<&TileType> {
+        self.types.iter().find(|tile| tile.id == id)
+    }
+
+    pub fn is_walkable(&self, id: u8) -> bool {
+        self.get(id).is_some_and(|tile| tile.walkable)
+    }
+
+    pub fn is_slippery(&self, id: u8) -> bool {
+        self.get(id).is_some_and(|tile| tile.slippery)
+    }
+
+    pub fn is_exit(&self, id: u8) -> bool {
+        self.get(id).is_some_and(|tile| tile.exit)
+    }
+
+    /// The first tile type marked as the exit, if any.
+    pub fn exit(&self) -> Option<&TileType> {
+        self.types.iter().find(|tile| tile.exit)
+    }
+
+    /// The first tile type marked as the spawn, if any.
+    pub fn spawn(&self) -> Option<&TileType> {
+        self.types.iter().find(|tile| tile.spawn)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(id: u8, name: &str) -> TileType {
+        TileType {
+            id,
+            name: name.to_owned(),
+            walkable: false,
+            opaque: false,
+            damaging: false,
+            slippery: false,
+            exit: false,
+            spawn: false,
+        }
+    }
+
+    #[test]
+    fn flags_combine_the_properties() {
+        assert_eq!(tile(0, "void").flags(), 0);
+        assert_eq!(
+            TileType {
+                opaque: true,
+                ..tile(3, "wall")
+            }
+            .flags(),
+            TileType::OPAQUE
+        );
+        assert_eq!(
+            TileType {
+                walkable: true,
+                damaging: true,
+                ..tile(4, "lava")
+            }
+            .flags(),
+            TileType::WALKABLE | TileType::DAMAGING
+        );
+        assert_eq!(
+            TileType {
+                walkable: true,
+                slippery: true,
+                ..tile(1, "ice")
+            }
+            .flags(),
+            TileType::WALKABLE | TileType::SLIPPERY
+        );
+        assert_eq!(
+            TileType {
+                walkable: true,
+                exit: true,
+                spawn: true,
+                ..tile(2, "gate")
+            }
+            .flags(),
+            TileType::WALKABLE | TileType::EXIT | TileType::SPAWN
+        );
+    }
+
+    #[test]
+    fn registry_looks_tiles_up_by_id() {
+        let registry =
+            TileRegistry::from(&super::super::super::config::WorldConfig::default().tiles);
+
+        assert_eq!(registry.get(1).map(|tile| tile.name.as_str()), Some("wall"));
+        assert!(registry
+            .get(1)
+            .is_some_and(|tile| tile.opaque && !tile.damaging));
+        assert!(registry.is_walkable(0));
+        assert!(!registry.is_walkable(1));
+        assert!(registry.is_exit(4));
+        assert_eq!(registry.exit().map(|tile| tile.id), Some(4));
+        assert_eq!(registry.spawn().map(|tile| tile.id), Some(5));
+    }
+
+    #[test]
+    fn unknown_ids_are_solid() {
+        let registry =
+            TileRegistry::from(&super::super::super::config::WorldConfig::default().tiles);
+
+        assert!(registry.get(200).is_none());
+        assert!(!registry.is_walkable(200));
+        assert!(!registry.is_slippery(200));
+        assert!(!registry.is_exit(200));
+    }
+}
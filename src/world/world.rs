@@ -1,62 +1,149 @@
+extern crate log;
+extern crate rand;
+extern crate rand_pcg;
+
+use rand::RngCore;
+use rand::SeedableRng;
+
+/// Inputs beyond this many pending moves are discarded instead of queued.
+pub const MAX_BUFFERED_INPUTS: usize = 4;
+
+/// A state change produced by `World::tick`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Move {
+        player: u64,
+        direction: super::object::Direction,
+    },
+    /// The block at `(x, y)` was replaced through `World::set_block`.
+    TileChanged { x: i32, y: i32, tile: u8 },
+    /// `player` reached the exit `ticks` ticks into the round; the next one starts `countdown`
+    /// ticks later.
+    RoundWon {
+        player: u64,
+        ticks: u64,
+        countdown: u32,
+    },
+    /// The map was replaced and every player sent back to the spawn.
+    RoundStarted,
+}
+
+/// Progress of the race on the current map.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Round {
+    /// Players are racing to the exit since tick `started`.
+    Running { started: u64 },
+    /// `winner` reached the exit; moves are ignored until tick `restart`. The next map is
+    /// generated in the background meanwhile, and the round starts late if it is not ready by
+    /// then.
+    Won { winner: u64, restart: u64 },
+}
+
+/// A map being generated on another thread, with the seed it is generated from.
+type NextMap = std::thread::JoinHandle<(
+    u64,
+    Result<(super::map::Map, usize), super::generator::GenerateError>,
+)>;
+
 pub struct World {
+    config: super::super::config::WorldConfig,
+    overlapping: Option<std::sync::Arc<super::generator::Overlapping>>,
+    next: Option<NextMap>,
+    seed: u64,
+    seeds: rand_pcg::Pcg64,
     map: super::map::Map,
     players: Vec<super::player::Player>,
+    spawn: (i32, i32),
+    exit: (i32, i32),
+    tiles: super::tile::TileRegistry,
+    path_length: usize,
+    exit_distances: Vec<Option<usize>>,
+    changes: Vec<Event>,
+    move_interval: u32,
+    round: Round,
+    tick: u64,
 }
 
 impl World {
-    pub fn new() -> World {
-        let mut generator = super::generator::Generator::new();
-
-        generator.add_tile(0, 2f32);
-        generator.add_tile(1, 4f32);
-        generator.add_tile(2, 4f32);
-        generator.add_tile(3, 1f32);
+    pub fn new(
+        config: &super::super::config::WorldConfig,
+    ) -> Result<World, super::generator::GenerateError> {
+        let seed = config.seed.unwrap_or_else(|| rand::thread_rng().next_u64());
+        let tiles = super::tile::TileRegistry::from(&config.tiles);
+        let overlapping = match &config.sample {
+            Some(sample) => {
+                let mut overlapping = super::generator::Overlapping::learn(
+                    &sample.blocks(),
+                    sample.width(),
+                    sample.height(),
+                    sample.pattern_size,
+                )?;
 
-        let mut constraints = std::collections::HashMap::new();
+                log::info!(
+                    "learned {} patterns from a {}x{} sample",
+                    overlapping.pattern_count(),
+                    sample.width(),
+                    sample.height()
+                );
 
-        constraints.insert(0, vec![0, 3]);
-        constraints.insert(1, vec![1, 2, 3]);
-        constraints.insert(2, vec![1, 2, 3]);
-        constraints.insert(3, vec![0, 1, 2, 3]);
+                overlapping.set_max_attempts(config.generation_attempts);
+                Some(std::sync::Arc::new(overlapping))
+            }
+            None => None,
+        };
+        let (map, path_length) = build(config, &tiles, overlapping.as_deref(), seed)?;
+        let exit_distances =
+            super::pathfinding::distance_field(&map, config.exit, |block| tiles.is_walkable(block));
 
-        let mut initials: Vec<Vec<bool>> = Vec::new();
+        Ok(World {
+            config: config.clone(),
+            overlapping,
+            next: None,
+            seed,
+            seeds: rand_pcg::Pcg64::seed_from_u64(seed),
+            map,
+            players: Vec::new(),
+            spawn: config.spawn,
+            exit: config.exit,
+            tiles,
+            path_length,
+            exit_distances,
+            changes: Vec::new(),
+            move_interval: config.move_interval,
+            round: Round::Running { started: 0 },
+            tick: 0,
+        })
+    }
 
-        for _ in 0..40 * 30 {
-            initials.push(vec![true; 4]);
-        }
+    /// The seed the current map was generated from; the same seed and configuration reproduce
+    /// it. The seeds of the following rounds' maps are drawn from the first one.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
 
-        for index in 0..40 * 30 {
-            let x = index % 40;
-            let y = index / 40;
+    pub fn round(&self) -> Round {
+        self.round
+    }
 
-            if x == 0 || x == 39 || y == 0 || y == 29 {
-                initials[index][0] = false;
-                initials[index][1] = false;
-                initials[index][2] = false;
-                initials[index][3] = true;
-            }
-        }
+    /// Number of moves on the shortest path from the spawn to the exit.
+    pub fn path_length(&self) -> usize {
+        self.path_length
+    }
 
-        initials[1 + 1 * 40][0] = true;
-        initials[1 + 1 * 40][1] = false;
-        initials[1 + 1 * 40][2] = false;
-        initials[1 + 1 * 40][3] = false;
+    /// The first move of a shortest path from the player's position to the exit, or `None` if
+    /// the player is unknown, already on the exit or cannot reach it.
+    pub fn hint(&self, id: u64) -> Option<super::object::Direction> {
+        let player = self.players.iter().find(|player| player.id() == id)?;
 
-        initials[38 + 28 * 40][0] = true;
-        initials[38 + 28 * 40][1] = false;
-        initials[38 + 28 * 40][2] = false;
-        initials[38 + 28 * 40][3] = false;
+        super::pathfinding::next_step(
+            &self.map,
+            &self.exit_distances,
+            (player.object().x, player.object().y),
+        )
+    }
 
-        World {
-            map: super::map::Map::from(
-                40,
-                30,
-                generator
-                    .generate(40, 30, &constraints, Some(initials))
-                    .unwrap(),
-            ),
-            players: Vec::new(),
-        }
+    pub fn tiles(&self) -> &super::tile::TileRegistry {
+        &self.tiles
     }
 
     pub fn map(&self) -> &super::map::Map {
@@ -71,8 +158,195 @@ impl World {
         &mut self.players
     }
 
-    pub fn add_player(&mut self, id: u64) {
-        self.players.push(super::player::Player::new(id, 1, 1));
+    pub fn tick_count(&self) -> u64 {
+        self.tick
+    }
+
+    /// Buffers a move for the next ticks; returns `false` if the player is unknown or its buffer
+    /// is full.
+    pub fn queue_input(&mut self, id: u64, direction: super::object::Direction) -> bool {
+        let inputs = match self.players.iter_mut().find(|player| player.id() == id) {
+            Some(player) => player.inputs_mut(),
+            None => return false,
+        };
+
+        if inputs.len() >= MAX_BUFFERED_INPUTS {
+            return false;
+        }
+
+        inputs.push_back(direction);
+        true
+    }
+
+    /// Advances the simulation by one step, applying at most one buffered move per player in
+    /// join order. Tile changes made since the previous tick come first. The first player to
+    /// step onto an exit tile wins the round, and the next one starts on a new map once the
+    /// countdown has elapsed.
+    pub fn tick(&mut self) -> Vec<Event> {
+        let mut events = std::mem::take(&mut self.changes);
+
+        self.tick += 1;
+
+        match self.round {
+            Round::Won { restart, .. }
+                if self.tick >= restart && self.next.as_ref().is_none_or(NextMap::is_finished) =>
+            {
+                self.next_round();
+                return vec![Event::RoundStarted];
+            }
+            Round::Won { .. } => {}
+            Round::Running { .. } => self.step(&mut events),
+        }
+
+        if events
+            .iter()
+            .any(|event| matches!(event, Event::TileChanged { .. }))
+        {
+            self.exit_distances =
+                super::pathfinding::distance_field(&self.map, self.exit, |block| {
+                    self.tiles.is_walkable(block)
+                });
+        }
+
+        events
+    }
+
+    /// Moves the players and ends the round if one of them reached the exit.
+    fn step(&mut self, events: &mut Vec<Event>) {
+        let mut winner = None;
+
+        for player in self.players.iter_mut() {
+            if player.cooldown() > 0 {
+                player.set_cooldown(player.cooldown() - 1);
+                continue;
+            }
+
+            let direction = match player.inputs_mut().pop_front() {
+                Some(direction) => direction,
+                None => continue,
+            };
+            let (dx, dy) = direction.offset();
+
+            let block = match self.map.get(player.object().x + dx, player.object().y + dy) {
+                Some(block) if self.tiles.is_walkable(block) => block,
+                _ => continue,
+            };
+
+            player.object_mut().x += dx;
+            player.object_mut().y += dy;
+            player.set_cooldown(self.move_interval - 1);
+
+            // Sliding takes precedence over whatever the player queued next.
+            if self.tiles.is_slippery(block) {
+                player.inputs_mut().push_front(direction);
+            }
+
+            events.push(Event::Move {
+                player: player.id(),
+                direction,
+            });
+
+            if winner.is_none() && self.tiles.is_exit(block) {
+                winner = Some(player.id());
+            }
+        }
+
+        let started = match self.round {
+            Round::Running { started } => started,
+            Round::Won { .. } => return,
+        };
+
+        if let Some(winner) = winner {
+            let ticks = self.tick - started;
+
+            log::info!("player {} reached the exit in {} ticks", winner, ticks);
+
+            self.prepare_next_round();
+            self.round = Round::Won {
+                winner,
+                restart: self.tick + self.config.round_countdown as u64,
+            };
+            events.push(Event::RoundWon {
+                player: winner,
+                ticks,
+                countdown: self.config.round_countdown,
+            });
+        }
+    }
+
+    /// Starts generating the map of the next round from the next seed on another thread, so
+    /// that ticks go on during the countdown.
+    fn prepare_next_round(&mut self) {
+        let seed = self.seeds.next_u64();
+        let config = self.config.clone();
+        let overlapping = self.overlapping.clone();
+        let generator = std::thread::Builder::new()
+            .name("generator".to_owned())
+            .spawn(move || {
+                let tiles = super::tile::TileRegistry::from(&config.tiles);
+
+                (seed, build(&config, &tiles, overlapping.as_deref(), seed))
+            });
+
+        match generator {
+            Ok(generator) => self.next = Some(generator),
+            Err(err) => log::error!("failed to start generating the next map: {}", err),
+        }
+    }
+
+    /// Replaces the map with the one `prepare_next_round` generated and sends every player back
+    /// to the spawn. The current map is kept if generation failed.
+    fn next_round(&mut self) {
+        match self.next.take().map(NextMap::join) {
+            Some(Ok((seed, Ok((map, path_length))))) => {
+                self.seed = seed;
+                self.map = map;
+                self.path_length = path_length;
+            }
+            Some(Ok((_, Err(err)))) => {
+                log::error!("failed to generate the next map, keeping this one: {}", err)
+            }
+            Some(Err(..)) => log::error!("generating the next map panicked, keeping this one"),
+            None => {}
+        }
+
+        self.changes.clear();
+        self.exit_distances = super::pathfinding::distance_field(&self.map, self.exit, |block| {
+            self.tiles.is_walkable(block)
+        });
+
+        for player in self.players.iter_mut() {
+            player.object_mut().x = self.spawn.0;
+            player.object_mut().y = self.spawn.1;
+            player.inputs_mut().clear();
+            player.set_cooldown(0);
+        }
+
+        self.round = Round::Running { started: self.tick };
+    }
+
+    /// Replaces the block at `(x, y)`; the change is reported by the next `tick`, which also
+    /// refreshes the hints. Returns `false` if the position lies outside the map.
+    pub fn set_block(&mut self, x: i32, y: i32, tile: u8) -> bool {
+        match self.map.get(x, y) {
+            Some(block) if block == tile => true,
+            Some(..) => {
+                self.map.set_block(x, y, tile);
+                self.changes.push(Event::TileChanged { x, y, tile });
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn add_player(&mut self, id: u64, name: String, color: Option<(u8, u8, u8)>) {
+        self.players.push(super::player::Player::new(
+            id,
+            name,
+            color,
+            self.spawn.0,
+            self.spawn.1,
+        ));
     }
 
     pub fn remove_player(&mut self, id: u64) -> bool {
@@ -85,3 +359,423 @@ impl World {
         }
     }
 }
+
+/// Generates a map from `seed`, connects it as configured and stamps the spawn and exit markers.
+/// Returns it with the number of moves from the spawn to the exit.
+fn build(
+    config: &super::super::config::WorldConfig,
+    tiles: &super::tile::TileRegistry,
+    overlapping: Option<&super::generator::Overlapping>,
+    seed: u64,
+) -> Result<(super::map::Map, usize), super::generator::GenerateError> {
+    let mut rng = rand_pcg::Pcg64::seed_from_u64(seed);
+    let floor = config.tiles[config.floor_tile].id;
+    let spawn = config.spawn;
+    let exit = config.exit;
+    let walkable = |block| tiles.is_walkable(block);
+    let mut attempts = 0;
+
+    let mut map = loop {
+        attempts += 1;
+
+        let mut map = super::map::Map::from(
+            config.width,
+            config.height,
+            generate(config, overlapping, &mut rng)?,
+        );
+
+        match config.connectivity {
+            super::super::config::Connectivity::Carve => {
+                let carved = super::connectivity::carve(&mut map, spawn, floor, walkable);
+
+                if carved > 0 {
+                    log::debug!("carved {} blocks to connect the map", carved);
+                }
+
+                break map;
+            }
+            super::super::config::Connectivity::Regenerate => {
+                if super::connectivity::shortest_path(&map, spawn, exit, walkable).is_some() {
+                    break map;
+                }
+
+                if attempts >= config.generation_attempts {
+                    return Err(super::generator::GenerateError::Disconnected { attempts });
+                }
+
+                log::debug!("map {} has an unreachable exit, regenerating", attempts);
+            }
+        }
+    };
+
+    if let Some(tile) = tiles.spawn() {
+        map.set_block(spawn.0, spawn.1, tile.id);
+    }
+
+    if let Some(tile) = tiles.exit() {
+        map.set_block(exit.0, exit.1, tile.id);
+    }
+
+    let path_length = super::connectivity::shortest_path(&map, spawn, exit, walkable)
+        .ok_or(super::generator::GenerateError::Disconnected { attempts })?;
+
+    log::info!(
+        "generated a {}x{} world from seed {}, the exit is {} moves from the spawn",
+        config.width,
+        config.height,
+        seed,
+        path_length
+    );
+
+    Ok((map, path_length))
+}
+
+/// Generates the blocks of one map, from the learned sample if there is one and from the
+/// configured tiles otherwise.
+fn generate(
+    config: &super::super::config::WorldConfig,
+    overlapping: Option<&super::generator::Overlapping>,
+    rng: &mut rand_pcg::Pcg64,
+) -> Result<Vec<u8>, super::generator::GenerateError> {
+    let width = config.width as usize;
+    let height = config.height as usize;
+    let floor = config.tiles[config.floor_tile].id;
+    let spawn = (config.spawn.0 as usize, config.spawn.1 as usize);
+    let exit = (config.exit.0 as usize, config.exit.1 as usize);
+
+    match overlapping {
+        Some(overlapping) => {
+            // The sample only shapes the inside of the map and the border is drawn around it,
+            // so the sample does not need walls long enough to line the whole map.
+            let margin = if config.border_tile.is_some() { 1 } else { 0 };
+            let border = config
+                .border_tile
+                .map_or(floor, |border| config.tiles[border].id);
+            let inner_width = width - 2 * margin;
+            let pins = [
+                (spawn.0 - margin, spawn.1 - margin, floor),
+                (exit.0 - margin, exit.1 - margin, floor),
+            ];
+            let inner = overlapping.generate(rng, inner_width, height - 2 * margin, &pins)?;
+
+            Ok((0..width * height)
+                .map(|index| {
+                    let x = index % width;
+                    let y = index / width;
+
+                    if x < margin || y < margin || x >= width - margin || y >= height - margin {
+                        border
+                    } else {
+                        inner[x - margin + (y - margin) * inner_width]
+                    }
+                })
+                .collect())
+        }
+        None => {
+            let (mut generator, rules) = build_tiles(config);
+            let ids = generator.tile_ids();
+            let mut initials = vec![vec![true; ids.len()]; width * height];
+
+            if let Some(border) = config.border_tile {
+                for (index, initial) in initials.iter_mut().enumerate() {
+                    let x = index % width;
+                    let y = index / width;
+
+                    if x == 0 || x == width - 1 || y == 0 || y == height - 1 {
+                        pin(initial, &ids, config.tiles[border].id);
+                    }
+                }
+            }
+
+            pin(&mut initials[spawn.0 + spawn.1 * width], &ids, floor);
+            pin(&mut initials[exit.0 + exit.1 * width], &ids, floor);
+
+            generator.set_max_attempts(config.generation_attempts);
+            generator.generate(rng, width, height, &rules, Some(initials))
+        }
+    }
+}
+
+/// Builds the generator from every configured tile except the exit and spawn markers.
+fn build_tiles(
+    config: &super::super::config::WorldConfig,
+) -> (super::generator::Generator, super::generator::Rules) {
+    let generated: Vec<usize> = (0..config.tiles.len())
+        .filter(|&index| !(config.tiles[index].exit || config.tiles[index].spawn))
+        .collect();
+    let generator_index = |tile: usize| generated.iter().position(|&index| index == tile);
+    let mut generator = super::generator::Generator::new();
+    let mut rules = super::generator::Rules::new(generated.len());
+
+    for (index, &tile) in generated.iter().enumerate() {
+        let tile = &config.tiles[tile];
+
+        generator.add_tile(tile.id, tile.weight);
+
+        for neighbor in tile
+            .neighbors
+            .iter()
+            .filter_map(|&tile| generator_index(tile))
+        {
+            rules.allow_all(index, neighbor);
+        }
+
+        for (direction, neighbors) in tile.directional_neighbors().iter() {
+            for neighbor in neighbors.iter().filter_map(|&tile| generator_index(tile)) {
+                rules.allow(index, *direction, neighbor);
+            }
+        }
+    }
+
+    (generator, rules)
+}
+
+/// Restricts a cell to the generator tiles that produce block `id`.
+fn pin(initial: &mut [bool], ids: &[u8], id: u8) {
+    for (possibility, tile_id) in initial.iter_mut().zip(ids.iter()) {
+        *possibility = *tile_id == id;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::object::Direction;
+    use super::*;
+
+    /// A world with a straight corridor of floor running right from the spawn, past the first
+    /// tick so that laying the corridor is no longer reported.
+    fn world() -> World {
+        let mut world = World::new(&super::super::super::config::WorldConfig {
+            seed: Some(7),
+            ..Default::default()
+        })
+        .unwrap();
+
+        for x in 2..8 {
+            world.set_block(x, 1, 0);
+        }
+
+        world.tick();
+        world
+    }
+
+    fn moves(events: &[Event]) -> Vec<u64> {
+        events
+            .iter()
+            .filter_map(|event| match *event {
+                Event::Move { player, .. } => Some(player),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_generates_the_same_world() {
+        let config = super::super::super::config::WorldConfig {
+            seed: Some(42),
+            ..Default::default()
+        };
+        let first = World::new(&config).unwrap();
+        let second = World::new(&config).unwrap();
+
+        assert_eq!(first.seed(), 42);
+        assert_eq!(first.map().data(), second.map().data());
+        assert_eq!(first.path_length(), second.path_length());
+
+        let other = World::new(&super::super::super::config::WorldConfig {
+            seed: Some(43),
+            ..config
+        })
+        .unwrap();
+
+        assert_ne!(first.map().data(), other.map().data());
+    }
+
+    #[test]
+    fn changed_blocks_are_reported_by_the_next_tick() {
+        let mut world = world();
+
+        assert!(world.set_block(3, 1, 1));
+        assert!(world.set_block(3, 1, 1));
+        assert!(!world.set_block(-1, 1, 1));
+        assert!(!world.set_block(40, 1, 1));
+        assert_eq!(world.map().get(3, 1), Some(1));
+        assert_eq!(
+            world.tick(),
+            vec![Event::TileChanged {
+                x: 3,
+                y: 1,
+                tile: 1
+            }]
+        );
+        assert_eq!(world.tick(), Vec::new());
+    }
+
+    #[test]
+    fn changed_blocks_refresh_the_hints() {
+        let mut world = world();
+        let exit = world.config.exit;
+
+        world.add_player(1, "Ariane".to_owned(), None);
+
+        let (x, y) = (world.players()[0].object().x, world.players()[0].object().y);
+        let (dx, dy) = world.hint(1).unwrap().offset();
+
+        // Walling off the first step of the shortest path leaves the hint stale until the tick.
+        world.set_block(x + dx, y + dy, 1);
+
+        assert_eq!(world.hint(1).map(Direction::offset), Some((dx, dy)));
+
+        world.tick();
+
+        let field = super::super::pathfinding::distance_field(world.map(), exit, |block| {
+            world.tiles().is_walkable(block)
+        });
+
+        assert_eq!(world.exit_distances, field);
+        assert_ne!(world.hint(1).map(Direction::offset), Some((dx, dy)));
+    }
+
+    #[test]
+    fn players_move_once_per_move_interval() {
+        let mut world = world();
+
+        world.add_player(1, "Ariane".to_owned(), None);
+
+        for _ in 0..3 {
+            assert!(world.queue_input(1, Direction::Right));
+        }
+
+        let moved: Vec<usize> = (0..6).map(|_| moves(&world.tick()).len()).collect();
+
+        assert_eq!(moved, vec![1, 0, 1, 0, 1, 0]);
+        assert_eq!(world.players()[0].object().x, 4);
+    }
+
+    #[test]
+    fn inputs_beyond_the_buffer_are_discarded() {
+        let mut world = world();
+
+        world.add_player(1, "Ariane".to_owned(), None);
+
+        for _ in 0..MAX_BUFFERED_INPUTS {
+            assert!(world.queue_input(1, Direction::Right));
+        }
+
+        assert!(!world.queue_input(1, Direction::Right));
+        assert!(!world.queue_input(2, Direction::Right));
+        assert_eq!(
+            world.players_mut()[0].inputs_mut().len(),
+            MAX_BUFFERED_INPUTS
+        );
+    }
+
+    #[test]
+    fn players_move_in_join_order() {
+        let mut world = world();
+
+        for &id in [3, 1, 2].iter() {
+            world.add_player(id, format!("player {}", id), None);
+            world.queue_input(id, Direction::Right);
+        }
+
+        assert_eq!(moves(&world.tick()), vec![3, 1, 2]);
+
+        // A player joining again goes last.
+        world.remove_player(1);
+        world.add_player(1, "player 1".to_owned(), None);
+        world.tick();
+
+        for &id in [1, 2, 3].iter() {
+            world.queue_input(id, Direction::Right);
+        }
+
+        assert_eq!(moves(&world.tick()), vec![3, 2, 1]);
+    }
+
+    #[test]
+    fn reaching_the_exit_wins_the_round_until_the_next_map() {
+        let mut world = world();
+        let seed = world.seed();
+
+        world.set_block(8, 1, 4);
+        world.add_player(1, "winner".to_owned(), None);
+        world.add_player(2, "idle".to_owned(), None);
+        world.tick();
+
+        let won = loop {
+            world.queue_input(1, Direction::Right);
+
+            let events = world.tick();
+
+            if let Some(&Event::RoundWon {
+                player,
+                ticks,
+                countdown,
+            }) = events.last()
+            {
+                assert_eq!(player, 1);
+                assert_eq!(ticks, world.tick_count());
+                assert_eq!(countdown, 100);
+                break world.tick_count();
+            }
+        };
+
+        assert_eq!(
+            world.round(),
+            Round::Won {
+                winner: 1,
+                restart: won + 100
+            }
+        );
+        assert_eq!(
+            (world.players()[0].object().x, world.players()[0].object().y),
+            (8, 1)
+        );
+
+        // Moves wait for the next round, which clears them.
+        world.queue_input(1, Direction::Left);
+        world.queue_input(2, Direction::Right);
+
+        loop {
+            let events = world.tick();
+
+            if events == vec![Event::RoundStarted] {
+                break;
+            }
+
+            assert_eq!(moves(&events), Vec::<u64>::new());
+            assert_eq!(world.players()[1].object().x, 1);
+
+            // The countdown may elapse before the next map is generated.
+            if world.tick_count() >= won + 100 {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        }
+
+        assert!(world.tick_count() >= won + 100);
+        assert_eq!(
+            world.round(),
+            Round::Running {
+                started: world.tick_count()
+            }
+        );
+
+        for player in world.players_mut().iter_mut() {
+            assert_eq!((player.object().x, player.object().y), (1, 1));
+            assert!(player.inputs_mut().is_empty());
+            assert_eq!(player.cooldown(), 0);
+        }
+
+        assert_ne!(world.seed(), seed);
+
+        let next = World::new(&super::super::super::config::WorldConfig {
+            seed: Some(world.seed()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(world.map().data(), next.map().data());
+        assert_eq!(world.path_length(), next.path_length());
+    }
+}